@@ -0,0 +1,232 @@
+// Inhibit while an application plays audio, for players that never inhibit themselves. Streams are watched through
+// pactl, which talks to PulseAudio and pipewire-pulse alike.
+use std::collections::HashMap;
+use std::io;
+use std::process::Stdio;
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+use tracing::{error, info, trace, warn};
+
+use crate::config::{self, Audio};
+use crate::inhibitor::{Inhibitors, Synthetic};
+
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// A sink input, i.e. a playback stream, as listed by `pactl -f json list sink-inputs`.
+#[derive(Debug, Deserialize)]
+struct SinkInput {
+    index: u32,
+    corked: bool,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+}
+
+impl SinkInput {
+    fn application_name(&self) -> Option<&str> {
+        self.properties.get("application.name").map(String::as_str)
+    }
+
+    fn binary(&self) -> Option<&str> {
+        self.properties.get("application.process.binary").map(String::as_str)
+    }
+}
+
+fn parse_sink_inputs(s: &[u8]) -> serde_json::Result<Vec<SinkInput>> {
+    serde_json::from_slice(s)
+}
+
+/// The name of the first configured application with an uncorked stream, if any.
+async fn playing(applications: &[regex::Regex]) -> anyhow::Result<Option<String>> {
+    let output = Command::new("pactl")
+        .args(["-f", "json", "list", "sink-inputs"])
+        // Decimals are formatted for the locale otherwise, which may not be valid JSON.
+        .env("LC_ALL", "C")
+        .output().await
+        .context("running pactl -f json list sink-inputs")?;
+    if !output.status.success() {
+        anyhow::bail!("pactl -f json list sink-inputs failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    let inputs = parse_sink_inputs(&output.stdout).context("parsing pactl -f json list sink-inputs")?;
+    trace!(?inputs, "Listed sink inputs");
+    Ok(find_playing(inputs, applications))
+}
+
+fn find_playing(inputs: Vec<SinkInput>, applications: &[regex::Regex]) -> Option<String> {
+    inputs.into_iter()
+        .filter(|x| !x.corked)
+        .find(|x| {
+            x.application_name().is_some_and(|y| config::any_matches(applications, y))
+                || x.binary().is_some_and(|y| config::any_matches(applications, y))
+        })
+        .map(|x| {
+            trace!(index=x.index, "Found a playing sink input");
+            x.application_name().or(x.binary()).unwrap_or("Unknown").to_string()
+        })
+}
+
+/// Follow `pactl subscribe` until terminated, or fail once it exits, e.g. when the sound server restarts.
+async fn watch_streams(
+    terminator: &mut watch::Receiver<bool>,
+    synthetic: &mut Synthetic,
+    config: &Audio,
+) -> anyhow::Result<()> {
+    let mut child = Command::new("pactl")
+        .arg("subscribe")
+        .env("LC_ALL", "C")
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().context("pactl subscribe has no stdout")?).lines();
+
+    // What is playing and since when, until it's been playing for long enough to inhibit.
+    let mut playing_since: Option<(String, Instant)> = None;
+    let mut refresh = true;
+    loop {
+        if refresh {
+            refresh = false;
+            match (playing(&config.application).await?, &playing_since) {
+                (Some(app), None) => {
+                    trace!(app, "Audio started playing");
+                    playing_since = Some((app, Instant::now()));
+                },
+                (Some(_), Some(_)) => (),
+                (None, Some((app, _))) => {
+                    info!(app, "Audio stopped playing, uninhibiting");
+                    playing_since = None;
+                    synthetic.set(false, "").await?;
+                },
+                (None, None) => (),
+            }
+        }
+
+        let deadline = playing_since.as_ref()
+            .filter(|_| !synthetic.is_held())
+            .map(|(_, since)| *since + config.min_duration);
+        tokio::select! {
+            x = terminator.changed() => {
+                x?;
+                // Terminator should only ever change from false to true.
+                assert!(*terminator.borrow());
+                return Ok(())
+            },
+            line = lines.next_line() => match line? {
+                // E.g. "Event 'change' on sink-input #42".
+                Some(x) => refresh = x.contains("sink-input"),
+                None => anyhow::bail!("pactl subscribe exited: {}", child.wait().await?),
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                if let Some((app, _)) = &playing_since {
+                    info!(app, "Audio has been playing long enough, inhibiting");
+                    synthetic.set(true, &format!("{} is playing audio", app)).await?;
+                }
+            },
+        }
+    }
+}
+
+/// Hold an inhibitor of our own while any of the configured applications has been playing audio for at least
+/// `min_duration`.
+pub(crate) async fn audio_task(
+    mut terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+    config: Audio,
+) -> anyhow::Result<()> {
+    info!("Starting audio inhibit task");
+    let mut synthetic = Synthetic::new(inhibitors, connection, "Audio");
+
+    let mut backoff = RESTART_BACKOFF_MIN;
+    loop {
+        match watch_streams(&mut terminator, &mut synthetic, &config).await {
+            Ok(()) => break,
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(|x| x.kind() == io::ErrorKind::NotFound) => {
+                error!("pactl not found, unable to inhibit for audio playback");
+                break
+            },
+            Err(e) => warn!(error=?e, retry_in=?backoff, "Lost track of audio streams"),
+        }
+        if let Err(e) = synthetic.set(false, "").await {
+            error!(error=?e, "Failed to release audio inhibitor");
+        }
+
+        tokio::select! {
+            x = terminator.changed() => {
+                x?;
+                assert!(*terminator.borrow());
+                break
+            },
+            _ = time::sleep(backoff) => (),
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+
+    info!("Stopping audio inhibit task");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pactl -f json list sink-inputs on PulseAudio 16.1, a corked Rhythmbox and a playing mpv.
+    const PULSEAUDIO: &str = r#"[{"index":3,"driver":"protocol-native.c","owner_module":"10","client":"12","sink":0,"sample_specification":"s16le 2ch 44100Hz","channel_map":"front-left,front-right","format":"pcm, format.sample_format = \"\\\"s16le\\\"\"  format.rate = \"44100\"  format.channels = \"2\"  format.channel_map = \"\\\"front-left,front-right\\\"\"","corked":true,"mute":false,"volume":{"front-left":{"value":65536,"value_percent":"100%","db":"0.00 dB"},"front-right":{"value":65536,"value_percent":"100%","db":"0.00 dB"}},"balance":0.00,"buffer_latency_usec":185759,"sink_latency_usec":17416,"resample_method":"speex-float-1","properties":{"media.role":"music","media.name":"Playback Stream","application.name":"Rhythmbox","native-protocol.peer":"UNIX socket client","native-protocol.version":"35","application.id":"org.gnome.Rhythmbox3","application.icon_name":"org.gnome.Rhythmbox3","application.process.id":"2741","application.process.user":"user","application.process.host":"laptop","application.process.binary":"rhythmbox","application.language":"C","window.x11.display":":0","application.process.machine_id":"5f0c8e4b3c2d4a1e9b7f6a5d4c3b2a19","module-stream-restore.id":"sink-input-by-media-role:music"}},{"index":7,"driver":"protocol-native.c","owner_module":"10","client":"15","sink":0,"sample_specification":"float32le 2ch 48000Hz","channel_map":"front-left,front-right","format":"pcm, format.sample_format = \"\\\"float32le\\\"\"  format.rate = \"48000\"  format.channels = \"2\"  format.channel_map = \"\\\"front-left,front-right\\\"\"","corked":false,"mute":false,"volume":{"front-left":{"value":65536,"value_percent":"100%","db":"0.00 dB"},"front-right":{"value":65536,"value_percent":"100%","db":"0.00 dB"}},"balance":0.00,"buffer_latency_usec":63333,"sink_latency_usec":17416,"resample_method":"copy","properties":{"media.name":"song.flac - mpv","application.name":"mpv Media Player","native-protocol.peer":"UNIX socket client","native-protocol.version":"35","application.id":"mpv","application.icon_name":"mpv","media.role":"video","application.process.id":"3120","application.process.user":"user","application.process.host":"laptop","application.process.binary":"mpv","application.language":"C","application.process.machine_id":"5f0c8e4b3c2d4a1e9b7f6a5d4c3b2a19","module-stream-restore.id":"sink-input-by-media-role:video"}}]"#;
+
+    // pactl -f json list sink-inputs on PipeWire 1.0.5 (pipewire-pulse), a playing Firefox.
+    const PIPEWIRE: &str = r#"[{"index":92,"driver":"PipeWire","owner_module":"","client":"91","sink":57,"sample_specification":"float32le 2ch 48000Hz","channel_map":"front-left,front-right","format":"pcm, format.sample_format = \"\\\"float32le\\\"\"  format.rate = \"48000\"  format.channels = \"2\"  format.channel_map = \"\\\"front-left,front-right\\\"\"","corked":false,"mute":false,"volume":{"front-left":{"value":65536,"value_percent":"100%","db":"0.00 dB"},"front-right":{"value":65536,"value_percent":"100%","db":"0.00 dB"}},"balance":0.00,"buffer_latency_usec":0,"sink_latency_usec":0,"resample_method":"PipeWire","properties":{"client.api":"pipewire-pulse","pulse.server.type":"unix","application.name":"Firefox","application.process.id":"4412","application.process.user":"user","application.process.host":"laptop","application.process.binary":"firefox","application.language":"en_US.UTF-8","window.x11.display":":0","application.process.machine_id":"5f0c8e4b3c2d4a1e9b7f6a5d4c3b2a19","application.process.session_id":"2","application.icon_name":"firefox","media.name":"AudioStream","node.rate":"1/48000","node.latency":"1024/48000","stream.is-live":"true","node.name":"Firefox","node.want-driver":"true","node.autoconnect":"true","media.class":"Stream/Output/Audio","adapt.follower.spa-node":"","object.register":"false","factory.id":"6","clock.quantum-limit":"8192","node.loop.name":"data-loop.0","library.name":"audioconvert/libspa-audioconvert","client.id":"91","object.id":"92","object.serial":"1416","target.object":"57","pulse.attr.maxlength":"4194304","pulse.attr.tlength":"20480","pulse.attr.prebuf":"4096","pulse.attr.minreq":"4096","module-stream-restore.id":"sink-input-by-application-name:Firefox"}}]"#;
+
+    fn patterns(x: &[&str]) -> Vec<regex::Regex> {
+        x.iter().map(|x| config::anchored(x).unwrap()).collect()
+    }
+
+    #[test]
+    fn pulseaudio() {
+        let inputs = parse_sink_inputs(PULSEAUDIO.as_bytes()).unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].index, 3);
+        assert!(inputs[0].corked);
+        assert_eq!(inputs[0].application_name(), Some("Rhythmbox"));
+        assert_eq!(inputs[0].binary(), Some("rhythmbox"));
+        assert_eq!(inputs[1].index, 7);
+        assert!(!inputs[1].corked);
+        assert_eq!(inputs[1].application_name(), Some("mpv Media Player"));
+    }
+
+    #[test]
+    fn pipewire() {
+        let inputs = parse_sink_inputs(PIPEWIRE.as_bytes()).unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].index, 92);
+        assert!(!inputs[0].corked);
+        assert_eq!(inputs[0].application_name(), Some("Firefox"));
+        assert_eq!(inputs[0].binary(), Some("firefox"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(parse_sink_inputs(b"[]").unwrap().is_empty());
+        assert!(parse_sink_inputs(b"Sink Input #3").is_err());
+    }
+
+    #[test]
+    fn corked_streams_dont_count() {
+        let inputs = || parse_sink_inputs(PULSEAUDIO.as_bytes()).unwrap();
+        assert_eq!(find_playing(inputs(), &patterns(&["Rhythmbox"])), None);
+        assert_eq!(find_playing(inputs(), &patterns(&["Rhythmbox", "mpv"])).as_deref(), Some("mpv Media Player"));
+    }
+
+    #[test]
+    fn matches_name_or_binary() {
+        let inputs = || parse_sink_inputs(PIPEWIRE.as_bytes()).unwrap();
+        assert_eq!(find_playing(inputs(), &patterns(&["Firefox"])).as_deref(), Some("Firefox"));
+        assert_eq!(find_playing(inputs(), &patterns(&["firefox"])).as_deref(), Some("Firefox"));
+        assert_eq!(find_playing(inputs(), &patterns(&["fire"])), None);
+        assert_eq!(find_playing(inputs(), &[]).as_deref(), Some("Firefox"));
+    }
+}
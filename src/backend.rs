@@ -0,0 +1,41 @@
+// Backends doing the actual inhibiting, selected at runtime.
+use std::fmt;
+
+use async_trait::async_trait;
+
+use crate::inhibitor::InhibitFlags;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(any(feature = "wayland", feature = "systemd")), allow(dead_code))]
+pub(crate) enum Health {
+    Healthy,
+    Unhealthy(String),
+}
+
+/// Everything a backend gets to know about an inhibit request.
+#[derive(Debug)]
+#[cfg_attr(not(any(feature = "wayland", feature = "systemd")), allow(dead_code))]
+pub(crate) struct Request<'a> {
+    pub cookie: u32,
+    pub application_name: &'a str,
+    pub reason: &'a str,
+    pub flags: InhibitFlags,
+}
+
+/// Backends keep track of what they're holding by cookie, and are only asked to acquire requests with one of the
+/// [`Backend::flags`] set.
+#[async_trait]
+pub(crate) trait Backend: fmt::Debug + Send + Sync {
+    /// Name used to enable the backend and in logs.
+    fn name(&self) -> &'static str;
+
+    /// The flags this backend can do something about.
+    fn flags(&self) -> InhibitFlags;
+
+    async fn acquire(&self, request: &Request<'_>) -> anyhow::Result<()>;
+
+    /// Release whatever was acquired for `cookie`, if anything.
+    async fn release(&self, cookie: u32) -> anyhow::Result<()>;
+
+    async fn health(&self) -> Health;
+}
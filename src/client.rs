@@ -0,0 +1,182 @@
+// Subcommands talking to the running daemon through io.github.wscreensaver_bridge.Control.
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use argh::FromArgs;
+use tokio::process::Command as ChildCommand;
+use tracing::warn;
+use zbus::fdo;
+use zbus_macros::proxy;
+
+use crate::control::{self, Inhibitor};
+use crate::inhibitor::InhibitFlags;
+
+#[proxy(
+    interface = "io.github.wscreensaver_bridge.Control",
+    default_service = "io.github.wscreensaver_bridge",
+    default_path = "/io/github/wscreensaver_bridge",
+    async_name = "Control",
+)]
+trait IoGithubWscreensaverBridgeControl {
+    fn inhibit(&self, application_name: &str, reason: &str, flags: u32) -> fdo::Result<u32>;
+    fn release(&self, cookie: u32) -> fdo::Result<()>;
+    fn list_inhibitors(&self) -> fdo::Result<Vec<Inhibitor>>;
+    fn get_backends(&self) -> fdo::Result<Vec<(String, bool, String)>>;
+    #[zbus(property)]
+    fn inhibited(&self) -> fdo::Result<bool>;
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub(crate) enum Command {
+    List(List),
+    Status(Status),
+    Release(Release),
+    Inhibit(Inhibit),
+}
+
+/// List the inhibitors held.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+pub(crate) struct List {}
+
+/// Show whether anything is inhibiting, and the health of the backends.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+pub(crate) struct Status {}
+
+/// Release an inhibitor, whoever holds it.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "release")]
+pub(crate) struct Release {
+    /// cookie of the inhibitor, as shown by list
+    #[argh(positional)]
+    cookie: u32,
+}
+
+/// Inhibit for as long as a command runs, e.g. `wscreensaver-bridge inhibit -- mpv video.mkv`.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "inhibit")]
+pub(crate) struct Inhibit {
+    /// application name to inhibit as (default: the command)
+    #[argh(option)]
+    app: Option<String>,
+    /// reason for inhibiting (default: the command line)
+    #[argh(option)]
+    reason: Option<String>,
+    /// what to inhibit, one of idle, suspend, logout, switch-user or automount, can be given multiple times
+    /// (default: idle)
+    #[argh(option, from_str_fn(parse_flag))]
+    what: Vec<InhibitFlags>,
+    /// command to run and its arguments
+    #[argh(positional, greedy)]
+    command: Vec<String>,
+}
+
+fn parse_flag(s: &str) -> Result<InhibitFlags, String> {
+    InhibitFlags::from_name(&s.to_uppercase().replace('-', "_"))
+        .ok_or_else(|| format!("unknown inhibit flag {:?}", s))
+}
+
+fn flag_names(flags: InhibitFlags) -> String {
+    flags.iter_names()
+        .map(|(x, _)| x.to_lowercase().replace('_', "-"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub(crate) async fn run(command: Command) -> anyhow::Result<()> {
+    let connection = zbus::Connection::session().await?;
+    let proxy = Control::new(&connection).await?;
+    // Fail early with a clear error rather than on the first call.
+    if !fdo::DBusProxy::new(&connection).await?.name_has_owner(control::NAME.try_into()?).await? {
+        anyhow::bail!("{} is not running, nobody owns {}", env!("CARGO_PKG_NAME"), control::NAME);
+    }
+
+    match command {
+        Command::List(List {}) => list(&proxy).await,
+        Command::Status(Status {}) => status(&proxy).await,
+        Command::Release(Release { cookie }) => proxy.release(cookie).await
+            .with_context(|| format!("releasing {}", cookie)),
+        Command::Inhibit(x) => inhibit(&proxy, x).await,
+    }
+}
+
+async fn list(proxy: &Control<'_>) -> anyhow::Result<()> {
+    let inhibitors = proxy.list_inhibitors().await?;
+    if inhibitors.is_empty() {
+        println!("No inhibitors");
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+    println!(
+        "{:<10} {:<8} {:>7} {:<15} {:<20} {:<12} {:>8} {:<16} REASON",
+        "COOKIE", "SENDER", "PID", "PROCESS", "APPLICATION", "WHAT", "AGE", "BACKENDS",
+    );
+    for x in inhibitors {
+        println!(
+            "{:<10} {:<8} {:>7} {:<15} {:<20} {:<12} {:>8} {:<16} {}",
+            x.cookie,
+            x.sender,
+            if x.pid == 0 { "-".to_string() } else { x.pid.to_string() },
+            if x.comm.is_empty() { "-" } else { &x.comm },
+            x.application_name,
+            flag_names(InhibitFlags::from_bits_truncate(x.flags)),
+            age(now.saturating_sub(x.created)),
+            x.backends.join(","),
+            x.reason,
+        );
+    }
+    Ok(())
+}
+
+fn age(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+async fn status(proxy: &Control<'_>) -> anyhow::Result<()> {
+    let inhibitors = proxy.list_inhibitors().await?;
+    if proxy.inhibited().await? {
+        println!("Inhibited by {} inhibitor(s)", inhibitors.len());
+    } else {
+        println!("Not inhibited");
+    }
+    for (name, healthy, error) in proxy.get_backends().await? {
+        if healthy {
+            println!("Backend {}: healthy", name);
+        } else {
+            println!("Backend {}: unhealthy, {}", name, error);
+        }
+    }
+    Ok(())
+}
+
+/// Hold an inhibitor while the command runs, exiting with its exit code. Should we get killed, the daemon releases
+/// the inhibitor once our connection goes away. Failing to release it, e.g. when it has expired in the mean time,
+/// doesn't change the exit code.
+async fn inhibit(proxy: &Control<'_>, args: Inhibit) -> anyhow::Result<()> {
+    let Some((program, program_args)) = args.command.split_first() else {
+        anyhow::bail!("No command given");
+    };
+    let flags = args.what.into_iter().fold(InhibitFlags::empty(), |acc, x| acc | x);
+    let flags = if flags.is_empty() { InhibitFlags::IDLE } else { flags };
+    let app = args.app.unwrap_or_else(|| program.clone());
+    let reason = args.reason.unwrap_or_else(|| args.command.join(" "));
+
+    let cookie = proxy.inhibit(&app, &reason, flags.bits()).await.context("inhibiting")?;
+
+    let status = ChildCommand::new(program).args(program_args).status().await
+        .with_context(|| format!("running {}", program));
+
+    if let Err(e) = proxy.release(cookie).await {
+        warn!(cookie, error=?e, "Failed to release the inhibitor");
+    }
+    // Killed by a signal if there's no exit code.
+    process::exit(status?.code().unwrap_or(1));
+}
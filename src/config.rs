@@ -0,0 +1,577 @@
+// Configuration file, $XDG_CONFIG_HOME/wscreensaver-bridge/config.toml unless given with --config.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use tracing::{info, warn};
+
+use crate::process::Process;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub policy: Policy,
+    pub fullscreen: Fullscreen,
+    pub audio: Audio,
+    pub mpris: Mpris,
+    pub idle: Idle,
+    pub user_activity: UserActivity,
+    pub lock: Lock,
+}
+
+impl Config {
+    /// Load the config from `path`, or from the default location if not given. A missing file is only an error if
+    /// `path` was given.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, required) = match path {
+            Some(x) => (x.to_path_buf(), true),
+            None => match default_path() {
+                Some(x) => (x, false),
+                None => {
+                    warn!("Neither XDG_CONFIG_HOME nor HOME set, not loading a config file");
+                    return Ok(Self::default());
+                },
+            },
+        };
+
+        let s = match fs::read_to_string(&path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                info!(path=%path.display(), "No config file found, using defaults");
+                return Ok(Self::default());
+            },
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        info!(path=%path.display(), "Loading config file");
+        Self::parse(&s).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        if config.idle.timeout.is_zero() {
+            anyhow::bail!("idle.timeout must be at least a second");
+        }
+        Ok(config)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))?;
+    Some(dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+}
+
+/// What to do with an inhibit request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum Action {
+    #[default]
+    Allow,
+    Deny,
+    /// Allow, but replace the application name and/or reason given.
+    Rewrite {
+        application_name: Option<String>,
+        reason: Option<String>,
+    },
+}
+
+/// The action as written in the config, the rewrite action takes its replacements from a table of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ActionName {
+    Allow,
+    Deny,
+    Rewrite,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rewrite {
+    application_name: Option<String>,
+    reason: Option<String>,
+}
+
+/// The outcome of [`Policy::check`].
+#[derive(Debug)]
+pub(crate) struct Verdict<'a> {
+    pub action: &'a Action,
+    /// Overrides the global maximum duration when set, zero meaning no limit.
+    pub max_duration: Option<Duration>,
+}
+
+/// A rule matches when every pattern given matches, patterns are regular expressions that must match the whole
+/// value.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RuleEntry")]
+struct Rule {
+    application_name: Option<Regex>,
+    reason: Option<Regex>,
+    app_id: Option<Regex>,
+    executable: Option<Regex>,
+    comm: Option<Regex>,
+    unit: Option<Regex>,
+    action: Action,
+    max_duration: Option<Duration>,
+}
+
+/// A rule as written in the config, checked and turned into a [`Rule`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    #[serde(default, deserialize_with = "pattern")]
+    application_name: Option<Regex>,
+    #[serde(default, deserialize_with = "pattern")]
+    reason: Option<Regex>,
+    #[serde(default, deserialize_with = "pattern")]
+    app_id: Option<Regex>,
+    #[serde(default, deserialize_with = "pattern")]
+    executable: Option<Regex>,
+    #[serde(default, deserialize_with = "pattern")]
+    comm: Option<Regex>,
+    #[serde(default, deserialize_with = "pattern")]
+    unit: Option<Regex>,
+    action: ActionName,
+    rewrite: Option<Rewrite>,
+    #[serde(default, deserialize_with = "optional_seconds")]
+    max_duration: Option<Duration>,
+}
+
+impl TryFrom<RuleEntry> for Rule {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: RuleEntry) -> anyhow::Result<Self> {
+        let action = match (entry.action, entry.rewrite) {
+            (ActionName::Allow, None) => Action::Allow,
+            (ActionName::Deny, None) => Action::Deny,
+            (ActionName::Rewrite, Some(x)) => Action::Rewrite { application_name: x.application_name, reason: x.reason },
+            (ActionName::Rewrite, None) => anyhow::bail!("rewrite action without a rewrite table"),
+            (_, Some(_)) => anyhow::bail!("rewrite table is only used by the rewrite action"),
+        };
+        if action == Action::Deny && entry.max_duration.is_some() {
+            anyhow::bail!("max_duration has no use with the deny action");
+        }
+        Ok(Self {
+            application_name: entry.application_name,
+            reason: entry.reason,
+            app_id: entry.app_id,
+            executable: entry.executable,
+            comm: entry.comm,
+            unit: entry.unit,
+            action,
+            max_duration: entry.max_duration,
+        })
+    }
+}
+
+impl Rule {
+    fn matches(&self, application_name: &str, reason: &str, app_id: Option<&str>, process: Option<&Process>) -> bool {
+        let executable = process.and_then(|x| x.executable.as_ref()).and_then(|x| x.to_str());
+        let comm = process.and_then(|x| x.comm.as_deref());
+        let unit = process.and_then(|x| x.unit.as_deref());
+        matches(&self.application_name, Some(application_name))
+            && matches(&self.reason, Some(reason))
+            && matches(&self.app_id, app_id)
+            && matches(&self.executable, executable)
+            && matches(&self.comm, comm)
+            && matches(&self.unit, unit)
+    }
+}
+
+/// A missing pattern matches anything, a missing value matches no pattern.
+fn matches(pattern: &Option<Regex>, value: Option<&str>) -> bool {
+    match (pattern, value) {
+        (None, _) => true,
+        (Some(x), Some(y)) => x.is_match(y),
+        (Some(_), None) => false,
+    }
+}
+
+/// Per-application policy for inhibit requests, the first matching rule decides.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Policy {
+    /// Action when no rule matches, either allow or deny.
+    #[serde(deserialize_with = "allow_or_deny")]
+    default: Action,
+    /// How long inhibitors are held for at most, for every interface.
+    #[serde(deserialize_with = "limit")]
+    max_duration: Option<Duration>,
+    #[serde(rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.max_duration
+    }
+
+    /// Decide what to do with an inhibit request. Executable, comm and unit rules never match without the process.
+    /// The app ID is the one given through the portal, or otherwise that of the Flatpak app the process belongs to.
+    pub fn check(
+        &self,
+        application_name: &str,
+        reason: &str,
+        app_id: Option<&str>,
+        process: Option<&Process>,
+    ) -> Verdict<'_> {
+        let app_id = app_id.or_else(|| process.and_then(Process::flatpak_app_id));
+        match self.rules.iter().find(|x| x.matches(application_name, reason, app_id, process)) {
+            Some(x) => Verdict { action: &x.action, max_duration: x.max_duration },
+            None => Verdict { action: &self.default, max_duration: None },
+        }
+    }
+}
+
+/// Inhibit while a window is fullscreen, tracked through wlr-foreign-toplevel-management.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Fullscreen {
+    pub enabled: bool,
+    /// Only windows with a matching app_id count, any window if empty.
+    #[serde(deserialize_with = "patterns")]
+    pub app_id: Vec<Regex>,
+}
+
+/// Inhibit while an application plays audio, watched through pactl.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Audio {
+    pub enabled: bool,
+    /// Only streams whose application name or binary matches count, any stream if empty.
+    #[serde(deserialize_with = "patterns")]
+    pub application: Vec<Regex>,
+    /// How long a stream has to play before inhibiting.
+    #[serde(deserialize_with = "seconds")]
+    pub min_duration: Duration,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            application: Vec::new(),
+            min_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Inhibit while an MPRIS media player is playing.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Mpris {
+    pub enabled: bool,
+    /// Only players whose bus name, minus the org.mpris.MediaPlayer2. prefix, matches count, any player if empty.
+    #[serde(deserialize_with = "patterns")]
+    pub player: Vec<Regex>,
+}
+
+/// Track whether the session is idle, for GetActive, GetSessionIdleTime and ActiveChanged.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Idle {
+    pub enabled: bool,
+    /// How long without input before the session counts as idle, and the screensaver as active.
+    #[serde(deserialize_with = "seconds")]
+    pub timeout: Duration,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// What SimulateUserActivity does.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UserActivity {
+    /// How long to inhibit for, zero to only create and destroy an inhibitor right away.
+    #[serde(deserialize_with = "seconds")]
+    pub hold: Duration,
+}
+
+/// How Lock locks the screen.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Lock {
+    /// Locker to run and its arguments, it must keep running until unlocked. Locks through systemd-logind if empty.
+    #[serde(deserialize_with = "command")]
+    pub command: Vec<String>,
+}
+
+/// Whether any of `patterns` matches `value`, or true if there are none.
+pub(crate) fn any_matches(patterns: &[Regex], value: &str) -> bool {
+    patterns.is_empty() || patterns.iter().any(|x| x.is_match(value))
+}
+
+/// Patterns have to match the whole value.
+pub(crate) fn anchored(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+fn allow_or_deny<'de, D: Deserializer<'de>>(d: D) -> Result<Action, D::Error> {
+    match ActionName::deserialize(d)? {
+        ActionName::Allow => Ok(Action::Allow),
+        ActionName::Deny => Ok(Action::Deny),
+        ActionName::Rewrite => Err(serde::de::Error::custom("expected allow or deny")),
+    }
+}
+
+fn seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    u64::deserialize(d).map(Duration::from_secs)
+}
+
+fn optional_seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    seconds(d).map(Some)
+}
+
+/// Seconds, zero meaning no limit.
+fn limit<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    seconds(d).map(|x| Some(x).filter(|x| !x.is_zero()))
+}
+
+/// A command given has to at least name the program.
+fn command<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    let command = Vec::<String>::deserialize(d)?;
+    if command.is_empty() {
+        return Err(serde::de::Error::custom("command must not be empty"));
+    }
+    Ok(command)
+}
+
+fn pattern<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    anchored(&String::deserialize(d)?).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// A single pattern, or an array of them.
+fn patterns<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Regex>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged, expecting = "a string or an array of strings")]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let patterns = match OneOrMany::deserialize(d)? {
+        OneOrMany::One(x) => vec![x],
+        OneOrMany::Many(x) => x,
+    };
+    patterns.iter().map(|x| anchored(x)).collect::<Result<_, _>>().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(executable: &str, comm: &str, unit: Option<&str>) -> Process {
+        Process {
+            pid: 1234,
+            uid: Some(1000),
+            executable: Some(PathBuf::from(executable)),
+            comm: Some(comm.to_string()),
+            unit: unit.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.policy.default, Action::Allow);
+        assert_eq!(config.policy.max_duration(), None);
+        assert!(config.policy.rules.is_empty());
+        assert!(!config.idle.enabled);
+        assert_eq!(config.idle.timeout, Duration::from_secs(300));
+        assert_eq!(config.audio.min_duration, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let config = Config::parse(r#"
+            [[policy.rule]]
+            executable = "/usr/bin/mpv"
+            action = "allow"
+            max_duration = 0
+
+            [[policy.rule]]
+            application_name = "(?i)mpv|slack"
+            action = "deny"
+        "#).unwrap();
+        let policy = &config.policy;
+
+        let verdict = policy.check("mpv", "Playing", None, Some(&process("/usr/bin/mpv", "mpv", None)));
+        assert_eq!(verdict.action, &Action::Allow);
+        assert_eq!(verdict.max_duration, Some(Duration::ZERO));
+
+        // Without the process the first rule can't match.
+        let verdict = policy.check("mpv", "Playing", None, None);
+        assert_eq!(verdict.action, &Action::Deny);
+
+        let verdict = policy.check("Slack", "Call", None, Some(&process("/usr/bin/slack", "slack", None)));
+        assert_eq!(verdict.action, &Action::Deny);
+        assert_eq!(verdict.max_duration, None);
+    }
+
+    #[test]
+    fn every_pattern_must_match() {
+        let config = Config::parse(r#"
+            [[policy.rule]]
+            comm = "firefox"
+            reason = "audio-playing"
+            action = "deny"
+        "#).unwrap();
+        let firefox = process("/usr/lib/firefox/firefox", "firefox", None);
+        assert_eq!(config.policy.check("Firefox", "audio-playing", None, Some(&firefox)).action, &Action::Deny);
+        assert_eq!(config.policy.check("Firefox", "video-playing", None, Some(&firefox)).action, &Action::Allow);
+    }
+
+    #[test]
+    fn app_id() {
+        let config = Config::parse(r#"
+            [[policy.rule]]
+            app_id = 'org\.mozilla\.firefox'
+            action = "deny"
+        "#).unwrap();
+        // Through the portal the process is the portal's, the app ID comes with the request.
+        assert_eq!(config.policy.check("", "", Some("org.mozilla.firefox"), None).action, &Action::Deny);
+        assert_eq!(config.policy.check("", "", Some("org.mozilla.Thunderbird"), None).action, &Action::Allow);
+        // Directly, it's that of the Flatpak app's scope.
+        let flatpak = process("/app/lib/firefox/firefox", "firefox", Some("app-flatpak-org.mozilla.firefox-42.scope"));
+        assert_eq!(config.policy.check("Firefox", "", None, Some(&flatpak)).action, &Action::Deny);
+        let native = process("/usr/lib/firefox/firefox", "firefox", Some("app-firefox-42.scope"));
+        assert_eq!(config.policy.check("Firefox", "", None, Some(&native)).action, &Action::Allow);
+        assert_eq!(config.policy.check("Firefox", "", None, None).action, &Action::Allow);
+    }
+
+    #[test]
+    fn patterns_match_whole_value() {
+        let config = Config::parse(r#"
+            [[policy.rule]]
+            unit = 'app-flatpak-.*\.scope'
+            action = "deny"
+        "#).unwrap();
+        let flatpak = process("/app/bin/app", "app", Some("app-flatpak-org.example.App-1234.scope"));
+        let service = process("/usr/bin/app", "app", Some("foo-app-flatpak-1.scope.service"));
+        assert_eq!(config.policy.check("App", "", None, Some(&flatpak)).action, &Action::Deny);
+        assert_eq!(config.policy.check("App", "", None, Some(&service)).action, &Action::Allow);
+    }
+
+    #[test]
+    fn default_action() {
+        let config = Config::parse(r#"
+            [policy]
+            default = "deny"
+
+            [[policy.rule]]
+            application_name = "mpv"
+            action = "allow"
+        "#).unwrap();
+        assert_eq!(config.policy.check("mpv", "", None, None).action, &Action::Allow);
+        let verdict = config.policy.check("vlc", "", None, None);
+        assert_eq!(verdict.action, &Action::Deny);
+        assert_eq!(verdict.max_duration, None);
+
+        assert!(Config::parse("[policy]\ndefault = \"rewrite\"").is_err());
+    }
+
+    #[test]
+    fn rewrite() {
+        let config = Config::parse(r#"
+            [[policy.rule]]
+            unit = 'app-flatpak-org\.chromium\.Chromium-.*\.scope'
+            action = "rewrite"
+            rewrite.application_name = "Chromium"
+
+            [[policy.rule]]
+            application_name = "vlc"
+            action = "rewrite"
+            rewrite = { reason = "Playing video" }
+        "#).unwrap();
+        let chromium = process("/app/chromium/chrome", "chrome", Some("app-flatpak-org.chromium.Chromium-42.scope"));
+        assert_eq!(config.policy.check("chrome", "WebRTC", None, Some(&chromium)).action, &Action::Rewrite {
+            application_name: Some("Chromium".to_string()),
+            reason: None,
+        });
+        assert_eq!(config.policy.check("vlc", "", None, None).action, &Action::Rewrite {
+            application_name: None,
+            reason: Some("Playing video".to_string()),
+        });
+    }
+
+    #[test]
+    fn rewrite_errors() {
+        // Rewrite without the table, the table without rewrite, and unknown keys in it.
+        assert!(Config::parse("[[policy.rule]]\naction = \"rewrite\"").is_err());
+        assert!(Config::parse("[[policy.rule]]\naction = \"allow\"\nrewrite.reason = \"x\"").is_err());
+        assert!(Config::parse("[[policy.rule]]\naction = \"rewrite\"\nrewrite.sender = \"x\"").is_err());
+    }
+
+    #[test]
+    fn max_duration() {
+        let config = Config::parse("[policy]\nmax_duration = 7200").unwrap();
+        assert_eq!(config.policy.max_duration(), Some(Duration::from_secs(7200)));
+
+        // Zero means no limit.
+        let config = Config::parse("[policy]\nmax_duration = 0").unwrap();
+        assert_eq!(config.policy.max_duration(), None);
+
+        assert!(Config::parse("[policy]\nmax_duration = -1").is_err());
+        assert!(Config::parse("[policy]\nmax_duration = \"1h\"").is_err());
+        assert!(Config::parse("[[policy.rule]]\naction = \"deny\"\nmax_duration = 10").is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert!(Config::parse("[screensaver]\nenabled = true").is_err());
+        assert!(Config::parse("verbose = true").is_err());
+        assert!(Config::parse("[policy]\ndefualt = \"deny\"").is_err());
+        assert!(Config::parse("[[policy.rule]]\naction = \"deny\"\npid = \"1\"").is_err());
+        assert!(Config::parse("[[policy.rule]]\nexecutable = \"mpv\"").is_err());
+        assert!(Config::parse("[idle]\ntimout = 10").is_err());
+        assert!(Config::parse("[lock]\ncommand = \"swaylock\"").is_err());
+    }
+
+    #[test]
+    fn sections() {
+        let config = Config::parse(r#"
+            [fullscreen]
+            enabled = true
+            app_id = "mpv"
+
+            [audio]
+            enabled = true
+            application = ["Spotify", "(?i)rhythmbox"]
+            min_duration = 10
+
+            [idle]
+            enabled = true
+            timeout = 600
+
+            [user_activity]
+            hold = 30
+
+            [lock]
+            command = ["swaylock", "--color", "000000"]
+        "#).unwrap();
+        assert!(config.fullscreen.enabled);
+        assert!(any_matches(&config.fullscreen.app_id, "mpv"));
+        assert!(!any_matches(&config.fullscreen.app_id, "mpv2"));
+        assert!(any_matches(&config.audio.application, "RhythmBox"));
+        assert_eq!(config.audio.min_duration, Duration::from_secs(10));
+        assert_eq!(config.idle.timeout, Duration::from_secs(600));
+        assert_eq!(config.user_activity.hold, Duration::from_secs(30));
+        assert_eq!(config.lock.command, ["swaylock", "--color", "000000"]);
+
+        assert!(Config::parse("[idle]\ntimeout = 0").is_err());
+        assert!(Config::parse("[lock]\ncommand = []").is_err());
+        assert!(Config::parse("[audio]\napplication = [\"(\"]").is_err());
+    }
+}
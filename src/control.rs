@@ -0,0 +1,216 @@
+// Our own interface, for seeing what is inhibiting and telling clients what happened to their inhibitors.
+use std::time::{Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use tracing::{error, info, instrument, trace, warn};
+use zbus::fdo;
+use zbus::message::Header;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::Type;
+use zbus_macros::interface;
+
+use crate::backend::Health;
+use crate::inhibitor::{InhibitFlags, InhibitorEvent, Inhibitors, Origin, StoredInhibitor};
+
+pub(crate) const NAME: &str = "io.github.wscreensaver_bridge";
+pub(crate) const PATH: &str = "/io/github/wscreensaver_bridge";
+
+/// An inhibitor as seen over D-Bus.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub(crate) struct Inhibitor {
+    pub cookie: u32,
+    pub sender: String,
+    /// Process and user ID of the sender, 0 if unknown.
+    pub pid: u32,
+    pub uid: u32,
+    /// Executable, process name and systemd unit of the sender, empty if unknown.
+    pub executable: String,
+    pub comm: String,
+    pub unit: String,
+    pub application_name: String,
+    pub reason: String,
+    /// GNOME session manager inhibit flags.
+    pub flags: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+    /// Names of the backends that acquired the inhibitor.
+    pub backends: Vec<String>,
+}
+
+impl Inhibitor {
+    fn new(cookie: u32, inhibitor: &StoredInhibitor) -> Self {
+        let process = inhibitor.process.as_ref();
+        Self {
+            cookie,
+            sender: inhibitor.sender.to_string(),
+            pid: process.map_or(0, |x| x.pid),
+            uid: process.and_then(|x| x.uid).unwrap_or_default(),
+            executable: process.and_then(|x| x.executable.as_ref())
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            comm: process.and_then(|x| x.comm.clone()).unwrap_or_default(),
+            unit: process.and_then(|x| x.unit.clone()).unwrap_or_default(),
+            application_name: inhibitor.application_name.clone(),
+            reason: inhibitor.reason.clone(),
+            flags: inhibitor.flags.bits(),
+            created: inhibitor.created.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs()),
+            backends: inhibitor.backends.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct IoGithubWscreensaverBridgeControlServer {
+    pub inhibitors: Inhibitors,
+}
+
+#[interface(name = "io.github.wscreensaver_bridge.Control")]
+impl IoGithubWscreensaverBridgeControlServer {
+    /// Inhibit with GNOME session manager `flags`, held until released or the caller disconnects.
+    #[instrument(skip(self, hdr, connection), fields(sender=?hdr.sender()))]
+    async fn inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
+        application_name: String,
+        reason: String,
+        flags: u32,
+    ) -> fdo::Result<u32> {
+        let Some(sender) = hdr.sender().map(|x| x.to_owned()) else {
+            let msg = "No sender provided";
+            error!(msg);
+            return Err(fdo::Error::Failed(msg.to_string()));
+        };
+
+        self.inhibitors.inhibit(connection, sender, &application_name, &reason, InhibitFlags::from_bits_truncate(flags), Origin::Client).await
+    }
+
+    /// Release any inhibitor, not just those held by the caller.
+    #[instrument(skip(self, hdr), fields(sender=?hdr.sender()))]
+    async fn release(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        cookie: u32,
+    ) -> fdo::Result<()> {
+        self.inhibitors.uninhibit(cookie).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_inhibitors(&self) -> fdo::Result<Vec<Inhibitor>> {
+        Ok(self.inhibitors.list()?
+            .iter()
+            .map(|(cookie, x)| Inhibitor::new(*cookie, x))
+            .collect())
+    }
+
+    /// Name of each backend in use, whether it's healthy and why not.
+    #[instrument(skip(self))]
+    async fn get_backends(&self) -> Vec<(String, bool, String)> {
+        self.inhibitors.health().await
+            .into_iter()
+            .map(|(name, health)| match health {
+                Health::Healthy => (name.to_string(), true, String::new()),
+                Health::Unhealthy(e) => (name.to_string(), false, e),
+            })
+            .collect()
+    }
+
+    #[zbus(property)]
+    async fn inhibited(&self) -> bool {
+        self.inhibitors.is_inhibited()
+    }
+
+    #[zbus(signal)]
+    async fn inhibitor_added(emitter: &SignalEmitter<'_>, inhibitor: Inhibitor) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn inhibitor_removed(emitter: &SignalEmitter<'_>, cookie: u32) -> zbus::Result<()>;
+
+    /// Sent only to the holder of the inhibitor, after it was released for exceeding its maximum duration.
+    #[zbus(signal)]
+    pub async fn inhibitor_expired(emitter: &SignalEmitter<'_>, cookie: u32) -> zbus::Result<()>;
+
+    /// The session has been idle for the configured timeout.
+    #[zbus(signal)]
+    async fn idled(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    /// There was input again after having been idle.
+    #[zbus(signal)]
+    async fn resumed(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+}
+
+/// Emit InhibitorAdded, InhibitorRemoved, Idled, Resumed and changes to Inhibited.
+pub(crate) async fn signal_task(
+    terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    idle: watch::Receiver<Option<Instant>>,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting control signal task");
+    let iface = connection.object_server().interface::<_, IoGithubWscreensaverBridgeControlServer>(PATH).await?;
+    let emitter = iface.signal_emitter();
+
+    enum Message {
+        Terminator(bool),
+        Event(Result<InhibitorEvent, BroadcastStreamRecvError>),
+        Inhibited(bool),
+        Idle(Option<Instant>),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(BroadcastStream::new(inhibitors.events()).map(Message::Event)));
+    stream.push(Box::pin(WatchStream::from_changes(inhibitors.subscribe()).map(Message::Inhibited)));
+    stream.push(Box::pin(WatchStream::from_changes(idle).map(Message::Idle)));
+
+    while let Some(msg) = stream.next().await {
+        let result = match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Event(Ok(InhibitorEvent::Added(cookie))) => {
+                // Already removed if missing, which we'll hear about next.
+                let Some(inhibitor) = inhibitors.get(cookie) else {
+                    continue
+                };
+                trace!(cookie, "Emitting InhibitorAdded");
+                IoGithubWscreensaverBridgeControlServer::inhibitor_added(emitter, Inhibitor::new(cookie, &inhibitor)).await
+            },
+            Message::Event(Ok(InhibitorEvent::Removed(cookie))) => {
+                trace!(cookie, "Emitting InhibitorRemoved");
+                IoGithubWscreensaverBridgeControlServer::inhibitor_removed(emitter, cookie).await
+            },
+            Message::Event(Err(BroadcastStreamRecvError::Lagged(n))) => {
+                warn!(n, "Missed inhibitor events, clients will have to call ListInhibitors to catch up");
+                continue
+            },
+            Message::Inhibited(x) => {
+                trace!(inhibited=x, "Emitting Inhibited change");
+                iface.get().await.inhibited_changed(emitter).await
+            },
+            Message::Idle(Some(_)) => {
+                trace!("Emitting Idled");
+                IoGithubWscreensaverBridgeControlServer::idled(emitter).await
+            },
+            Message::Idle(None) => {
+                trace!("Emitting Resumed");
+                IoGithubWscreensaverBridgeControlServer::resumed(emitter).await
+            },
+        };
+        if let Err(e) = result {
+            error!(error=?e, "Failed to emit control signal");
+        }
+    }
+
+    info!("Stopping control signal task");
+    Ok(())
+}
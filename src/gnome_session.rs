@@ -0,0 +1,231 @@
+// The inhibit parts of org.gnome.SessionManager, used by gtk_application_inhibit.
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use tracing::{error, info, instrument, trace, warn};
+use zbus::message::Header;
+use zbus::fdo;
+use zbus::object_server::{ObjectServer, SignalEmitter};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath};
+use zbus_macros::interface;
+
+use crate::inhibitor::{InhibitFlags, InhibitorEvent, Inhibitors, Origin};
+
+pub(crate) const NAME: &str = "org.gnome.SessionManager";
+pub(crate) const PATH: &str = "/org/gnome/SessionManager";
+
+fn inhibitor_path(cookie: u32) -> OwnedObjectPath {
+    ObjectPath::try_from(format!("{}/Inhibitor{}", PATH, cookie))
+        .expect("inhibitor path should be valid")
+        .into()
+}
+
+/// An inhibitor object as exported by gnome-session for each cookie.
+#[derive(Debug)]
+struct OrgGnomeSessionManagerInhibitor {
+    app_id: String,
+    reason: String,
+    flags: u32,
+    toplevel_xid: u32,
+}
+
+#[interface(name = "org.gnome.SessionManager.Inhibitor")]
+impl OrgGnomeSessionManagerInhibitor {
+    async fn get_app_id(&self) -> String {
+        self.app_id.clone()
+    }
+
+    /// We don't do client registration, so there's no client object to point to.
+    async fn get_client_id(&self) -> OwnedObjectPath {
+        ObjectPath::from_static_str_unchecked("/").into()
+    }
+
+    async fn get_reason(&self) -> String {
+        self.reason.clone()
+    }
+
+    async fn get_flags(&self) -> u32 {
+        self.flags
+    }
+
+    async fn get_toplevel_xid(&self) -> u32 {
+        self.toplevel_xid
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OrgGnomeSessionManagerServer {
+    pub inhibitors: Inhibitors,
+    /// Cookies of the inhibitors we've exported an object for.
+    pub exported: Arc<Mutex<BTreeSet<u32>>>,
+}
+
+impl OrgGnomeSessionManagerServer {
+    /// Returns whether it was exported.
+    fn unexport(&self, cookie: u32) -> fdo::Result<bool> {
+        Ok(self.exported.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on exported inhibitors: {:?}", e)))?
+            .remove(&cookie))
+    }
+}
+
+#[interface(name = "org.gnome.SessionManager")]
+impl OrgGnomeSessionManagerServer {
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, hdr, connection, server, emitter), fields(sender=?hdr.sender()))]
+    async fn inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
+        #[zbus(object_server)]
+        server: &ObjectServer,
+        #[zbus(signal_emitter)]
+        emitter: SignalEmitter<'_>,
+        app_id: String,
+        toplevel_xid: u32,
+        reason: String,
+        flags: u32,
+    ) -> fdo::Result<u32> {
+        let Some(sender) = hdr.sender().map(|x| x.to_owned()) else {
+            let msg = "No sender provided";
+            error!(msg);
+            return Err(fdo::Error::Failed(msg.to_string()));
+        };
+
+        let cookie = self.inhibitors.inhibit(connection, sender, &app_id, &reason, InhibitFlags::from_bits_truncate(flags), Origin::Client).await?;
+
+        let path = inhibitor_path(cookie);
+        if let Err(e) = server.at(&path, OrgGnomeSessionManagerInhibitor { app_id, reason, flags, toplevel_xid }).await {
+            error!(error=?e, "Failed to export inhibitor object");
+            self.inhibitors.uninhibit(cookie).await?;
+            return Err(e.into());
+        }
+        self.exported.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on exported inhibitors: {:?}", e)))?
+            .insert(cookie);
+        // Released while exporting, before inhibitor_object_task could tell it was exported. Whoever takes it out of
+        // exported removes the object.
+        if !self.inhibitors.contains(cookie) && self.unexport(cookie)? {
+            trace!(cookie, %path, "Inhibitor released while exporting, removing inhibitor object");
+            if let Err(e) = server.remove::<OrgGnomeSessionManagerInhibitor, _>(&path).await {
+                error!(cookie, error=?e, "Failed to remove inhibitor object");
+            }
+            return Ok(cookie);
+        }
+        if let Err(e) = Self::inhibitor_added(&emitter, path.as_ref()).await {
+            error!(error=?e, "Failed to emit InhibitorAdded");
+        }
+
+        Ok(cookie)
+    }
+
+    #[instrument(skip(self, hdr), fields(uninhibit_sender=?hdr.sender()))]
+    async fn uninhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        inhibit_cookie: u32,
+    ) -> fdo::Result<()> {
+        // The exported object gets cleaned up by inhibitor_object_task.
+        self.inhibitors.uninhibit(inhibit_cookie).await
+    }
+
+    #[instrument(skip(self))]
+    async fn is_inhibited(&self, flags: u32) -> fdo::Result<bool> {
+        Ok(self.inhibitors.inhibited_flags()?.intersects(InhibitFlags::from_bits_truncate(flags)))
+    }
+
+    #[instrument(skip(self))]
+    async fn get_inhibitors(&self) -> fdo::Result<Vec<OwnedObjectPath>> {
+        let exported = self.exported.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on exported inhibitors: {:?}", e)))?;
+        Ok(exported.iter().copied().map(inhibitor_path).collect())
+    }
+
+    #[zbus(property)]
+    async fn inhibited_actions(&self) -> fdo::Result<u32> {
+        Ok(self.inhibitors.inhibited_flags()?.bits())
+    }
+
+    #[zbus(signal)]
+    async fn inhibitor_added(emitter: &SignalEmitter<'_>, id: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn inhibitor_removed(emitter: &SignalEmitter<'_>, id: ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+/// Remove the exported inhibitor objects once their inhibitors are gone, whether through Uninhibit or the client
+/// disconnecting, and keep InhibitedActions up to date.
+pub(crate) async fn inhibitor_object_task(
+    terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    exported: Arc<Mutex<BTreeSet<u32>>>,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting org.gnome.SessionManager inhibitor object task");
+    let iface = connection.object_server().interface::<_, OrgGnomeSessionManagerServer>(PATH).await?;
+
+    enum Message {
+        Terminator(bool),
+        Event(Result<InhibitorEvent, BroadcastStreamRecvError>),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(BroadcastStream::new(inhibitors.events()).map(Message::Event)));
+
+    while let Some(msg) = stream.next().await {
+        let removed: Vec<u32> = match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Event(Ok(InhibitorEvent::Added(cookie))) => {
+                trace!(cookie, "Inhibitor added");
+                Vec::new()
+            },
+            Message::Event(Ok(InhibitorEvent::Removed(cookie))) => vec![cookie],
+            Message::Event(Err(BroadcastStreamRecvError::Lagged(n))) => {
+                warn!(n, "Missed inhibitor events, checking all exported inhibitors");
+                exported.lock()
+                    .map_err(|e| anyhow::anyhow!("Exported inhibitors lock error: {:?}", e))?
+                    .iter()
+                    .copied()
+                    .filter(|x| !inhibitors.contains(*x))
+                    .collect()
+            },
+        };
+
+        for cookie in removed {
+            let was_exported = exported.lock()
+                .map_err(|e| anyhow::anyhow!("Exported inhibitors lock error: {:?}", e))?
+                .remove(&cookie);
+            if !was_exported {
+                continue
+            }
+
+            let path = inhibitor_path(cookie);
+            trace!(cookie, %path, "Removing inhibitor object");
+            if let Err(e) = connection.object_server().remove::<OrgGnomeSessionManagerInhibitor, _>(&path).await {
+                error!(cookie, error=?e, "Failed to remove inhibitor object");
+            }
+            if let Err(e) = OrgGnomeSessionManagerServer::inhibitor_removed(iface.signal_emitter(), path.as_ref()).await {
+                error!(error=?e, "Failed to emit InhibitorRemoved");
+            }
+        }
+
+        if let Err(e) = iface.get().await.inhibited_actions_changed(iface.signal_emitter()).await {
+            error!(error=?e, "Failed to emit InhibitedActions change");
+        }
+    }
+
+    info!("Stopping org.gnome.SessionManager inhibitor object task");
+    Ok(())
+}
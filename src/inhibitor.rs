@@ -0,0 +1,739 @@
+// Inhibitor bookkeeping shared by every D-Bus interface we serve.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bitflags::bitflags;
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Duration, Instant};
+use tracing::{error, info, trace, warn};
+use zbus::fdo;
+use zbus::names::UniqueName;
+
+use crate::backend::{Backend, Health, Request};
+use crate::config::{Action, Policy, Verdict};
+use crate::process::Process;
+
+bitflags! {
+    /// What to inhibit, the bits match the flags of org.gnome.SessionManager.Inhibit.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) struct InhibitFlags: u32 {
+        const LOGOUT = 1;
+        const SWITCH_USER = 2;
+        const SUSPEND = 4;
+        const IDLE = 8;
+        const AUTOMOUNT = 16;
+    }
+}
+
+/// Where an inhibit request comes from, deciding whether the policy applies to it.
+#[derive(Debug, Clone)]
+pub(crate) enum Origin {
+    /// A client on the bus, subject to the policy.
+    Client,
+    /// A sandboxed app through xdg-desktop-portal, by its app ID, subject to the policy. The sender is the portal
+    /// itself, so its process isn't the app's.
+    Portal(String),
+    /// The bridge itself, exempt from the policy and held for at most the given duration, zero meaning no limit.
+    Bridge(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum InhibitorEvent {
+    Added(u32),
+    Removed(u32),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StoredInhibitor {
+    pub sender: UniqueName<'static>,
+    /// The process behind the sender, if the bus would tell.
+    pub process: Option<Process>,
+    pub application_name: String,
+    pub reason: String,
+    pub flags: InhibitFlags,
+    pub created: SystemTime,
+    /// Names of the backends that acquired this inhibitor.
+    pub backends: Vec<&'static str>,
+    /// When the inhibitor gets released even if the sender hasn't asked for it.
+    pub expires: Option<Instant>,
+    pub origin: Origin,
+}
+
+impl fmt::Display for StoredInhibitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}) held by {}", self.application_name, self.reason, self.sender)?;
+        if let Some(process) = &self.process {
+            write!(f, " {}", process)?;
+        }
+        Ok(())
+    }
+}
+
+/// Inhibitors by cookie, cloned into every interface so that a cookie handed out by one can be released by another.
+#[derive(Debug, Clone)]
+pub(crate) struct Inhibitors {
+    backends: Arc<[Box<dyn Backend>]>,
+    // NOTE: Must not be held across await points.
+    by_cookie: Arc<Mutex<HashMap<u32, StoredInhibitor>>>,
+    /// Cookies handed to the backends, but not yet in `by_cookie`.
+    reserved: Arc<Mutex<HashSet<u32>>>,
+    /// Whether any inhibitors are held.
+    inhibited: watch::Sender<bool>,
+    events: broadcast::Sender<InhibitorEvent>,
+    /// Decides on every request from a client, whichever interface it came through.
+    policy: Arc<Policy>,
+}
+
+impl Inhibitors {
+    pub fn new(backends: Vec<Box<dyn Backend>>, policy: Policy) -> Self {
+        Self {
+            backends: backends.into(),
+            by_cookie: Arc::new(Mutex::new(HashMap::new())),
+            reserved: Arc::new(Mutex::new(HashSet::new())),
+            inhibited: watch::Sender::new(false),
+            events: broadcast::Sender::new(16),
+            policy: Arc::new(policy),
+        }
+    }
+
+    /// Receive changes in whether any inhibitors are held.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inhibited.subscribe()
+    }
+
+    /// Receive inhibitors being added and removed.
+    pub fn events(&self) -> broadcast::Receiver<InhibitorEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn is_inhibited(&self) -> bool {
+        *self.inhibited.borrow()
+    }
+
+    pub fn contains(&self, cookie: u32) -> bool {
+        self.by_cookie.lock().is_ok_and(|x| x.contains_key(&cookie))
+    }
+
+    /// Union of the flags of every inhibitor held.
+    pub fn inhibited_flags(&self) -> fdo::Result<InhibitFlags> {
+        let by_cookie = self.by_cookie.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on inhibitors map: {:?}", e)))?;
+        Ok(by_cookie.values().fold(InhibitFlags::empty(), |acc, x| acc | x.flags))
+    }
+
+    pub fn get(&self, cookie: u32) -> Option<StoredInhibitor> {
+        self.by_cookie.lock().ok()?.get(&cookie).cloned()
+    }
+
+    /// Every inhibitor held, oldest first.
+    pub fn list(&self) -> fdo::Result<Vec<(u32, StoredInhibitor)>> {
+        let by_cookie = self.by_cookie.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on inhibitors map: {:?}", e)))?;
+        let mut inhibitors: Vec<_> = by_cookie.iter().map(|(x, y)| (*x, y.clone())).collect();
+        inhibitors.sort_by_key(|(_, x)| x.created);
+        Ok(inhibitors)
+    }
+
+    /// Have the inhibitor expire `duration` from now instead. Returns false if it's not held anymore.
+    pub fn extend(&self, cookie: u32, duration: Duration) -> bool {
+        let Ok(mut by_cookie) = self.by_cookie.lock() else {
+            return false
+        };
+        by_cookie.get_mut(&cookie)
+            .map(|x| x.expires = Some(Instant::now() + duration))
+            .is_some()
+    }
+
+    /// When the next inhibitor expires, if any will.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.by_cookie.lock().ok()?.values().filter_map(|x| x.expires).min()
+    }
+
+    pub async fn health(&self) -> Vec<(&'static str, Health)> {
+        let mut health = Vec::with_capacity(self.backends.len());
+        for backend in self.backends.iter() {
+            health.push((backend.name(), backend.health().await));
+        }
+        health
+    }
+
+    /// Acquire the inhibitor from every backend that handles one of `flags`. Succeeds as long as one of the backends
+    /// did, or if there were none to ask. Requests from clients are denied, rewritten or limited as the policy says.
+    pub async fn inhibit(
+        &self,
+        bus: &dyn Bus,
+        sender: UniqueName<'static>,
+        application_name: &str,
+        reason_for_inhibit: &str,
+        flags: InhibitFlags,
+        origin: Origin,
+    ) -> fdo::Result<u32> {
+        // Looked up once, for both the policy and the record.
+        let process = match bus.process(&sender).await {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!(error=?e, %sender, "Unable to find the sender's process, executable, comm and unit rules won't match");
+                None
+            },
+        };
+
+        let verdict = match &origin {
+            Origin::Client => self.policy.check(application_name, reason_for_inhibit, None, process.as_ref()),
+            // The process is the portal's, not the app's.
+            Origin::Portal(app_id) => self.policy.check(application_name, reason_for_inhibit, Some(app_id), None),
+            Origin::Bridge(x) => Verdict { action: &Action::Allow, max_duration: Some(*x) },
+        };
+        let (application_name, reason_for_inhibit) = match verdict.action {
+            Action::Allow => (application_name, reason_for_inhibit),
+            Action::Deny => {
+                info!("Inhibit denied by policy");
+                return Err(fdo::Error::AccessDenied("Inhibit denied by policy".to_string()));
+            },
+            Action::Rewrite { application_name: new_name, reason: new_reason } => {
+                info!(?new_name, ?new_reason, "Inhibit rewritten by policy");
+                (new_name.as_deref().unwrap_or(application_name), new_reason.as_deref().unwrap_or(reason_for_inhibit))
+            },
+        };
+
+        let cookie = self.reserve().map_err(|e| {
+            error!(error=?e, "Unable to retain the inhibitor");
+            fdo::Error::Failed(format!("Unable to retain the inhibitor: {}", e))
+        })?;
+
+        let request = Request {
+            cookie,
+            application_name,
+            reason: reason_for_inhibit,
+            flags,
+        };
+        let mut acquired = Vec::new();
+        let mut failed = Vec::new();
+        for backend in self.backends.iter().filter(|x| x.flags().intersects(flags)) {
+            match backend.acquire(&request).await {
+                Ok(()) => acquired.push(backend.name()),
+                Err(e) => {
+                    error!(cookie, backend=backend.name(), error=?e, "Failed to acquire inhibitor");
+                    failed.push(format!("{}: {:?}", backend.name(), e));
+                },
+            }
+        }
+
+        let unsupported = self.backends.iter().fold(flags, |acc, x| acc - x.flags());
+        if !unsupported.is_empty() {
+            warn!(cookie, flags=?unsupported, "No backend can inhibit these, ignoring them");
+        }
+
+        if acquired.is_empty() && !failed.is_empty() {
+            self.unreserve(cookie);
+            return Err(fdo::Error::Failed(format!("Failed to create inhibitor: {}", failed.join(", "))));
+        } else if !failed.is_empty() {
+            warn!(cookie, ?acquired, "Only some of the backends acquired the inhibitor");
+        }
+
+        let max_duration = verdict.max_duration.or(self.policy.max_duration()).filter(|x| !x.is_zero());
+        let inhibitor = StoredInhibitor {
+            sender: sender.clone(),
+            process: process.clone(),
+            application_name: application_name.to_string(),
+            reason: reason_for_inhibit.to_string(),
+            flags,
+            created: SystemTime::now(),
+            backends: acquired,
+            expires: max_duration.map(|x| Instant::now() + x),
+            origin,
+        };
+        if let Err(e) = self.insert(cookie, inhibitor.clone()) {
+            error!(error=?e, "Unable to retain the inhibitor");
+            self.unreserve(cookie);
+            let _ = self.release(cookie, &inhibitor).await;
+            return Err(fdo::Error::Failed(format!("Unable to retain the inhibitor: {}", e)));
+        }
+
+        // The sender may have disconnected while we were busy with the backends, after the clean up task already
+        // looked for its inhibitors. Any later and the clean up task takes care of it.
+        if !bus.is_connected(&sender).await {
+            info!(cookie, %sender, "Sender disconnected while inhibiting, uninhibiting");
+            if let Err(e) = self.remove_sender(&sender).await {
+                error!(cookie, error=?e, "Failed to remove the inhibitors of the disconnected sender");
+            }
+            return Err(fdo::Error::Failed(format!("{} disconnected", sender)));
+        }
+
+        info!(
+            cookie,
+            ?flags,
+            ?max_duration,
+            process=process.as_ref().map(|x| x.to_string()),
+            "Inhibiting screensaver for {} because {}.", application_name, reason_for_inhibit,
+        );
+
+        Ok(cookie)
+    }
+
+    pub async fn uninhibit(&self, cookie: u32) -> fdo::Result<()> {
+        let inhibitor = {
+            let mut by_cookie = self.by_cookie.lock()
+                .map_err(|e| {
+                    error!(error=?e, "Could not obtain lock for inhibitors map");
+                    fdo::Error::Failed(format!("Could not obtain lock on inhibitors map for clean up: {:?}", e))
+                })?;
+            let Some(inhibitor) = by_cookie.remove(&cookie) else {
+                error!("Cookie not found");
+                return Err(fdo::Error::Failed(format!("No inhibitor with cookie {}", cookie)));
+            };
+            self.removed(cookie, &by_cookie);
+            inhibitor
+        };
+        info!(cookie, %inhibitor, "Uninhibiting");
+
+        self.release(cookie, &inhibitor).await
+            .map_err(|e| fdo::Error::Failed(format!("Failed to destroy inhibitor: {}", e)))
+    }
+
+    /// Uninhibit everything held by `name`, as it has disconnected from the bus.
+    pub async fn remove_sender(&self, name: &UniqueName<'_>) -> anyhow::Result<()> {
+        let removed = {
+            let mut by_cookie = self.by_cookie.lock()
+                .map_err(|e| anyhow::anyhow!("Inhibitors map lock error: {:?}", e))?;
+            let cookies: Vec<u32> = by_cookie.iter()
+                .filter(|(_, x)| &x.sender == name)
+                .map(|(x, _)| *x)
+                .collect();
+            self.remove_all(&mut by_cookie, cookies)
+        };
+
+        for (cookie, inhibitor) in removed {
+            info!(cookie, %inhibitor, "Sender disconnected, uninhibiting");
+            let _ = self.release(cookie, &inhibitor).await;
+        }
+        Ok(())
+    }
+
+    /// Uninhibit everything held by senders not in `names`. Gives up and returns `Ok(false)` if the inhibitors map is
+    /// already locked.
+    pub async fn try_retain_senders(&self, names: &HashSet<UniqueName<'static>>) -> anyhow::Result<bool> {
+        let removed = {
+            let mut by_cookie = match self.by_cookie.try_lock() {
+                Ok(x) => x,
+                Err(TryLockError::WouldBlock) => return Ok(false),
+                Err(e) => anyhow::bail!(format!("Inhibitors map lock error: {:?}", e)),
+            };
+            let cookies: Vec<u32> = by_cookie.iter()
+                .filter(|(cookie, inhibitor)| if names.contains(&inhibitor.sender) {
+                    trace!(cookie, sender=%inhibitor.sender, "Sender still connected, keeping inhibitor alive");
+                    false
+                } else {
+                    true
+                })
+                .map(|(x, _)| *x)
+                .collect();
+            self.remove_all(&mut by_cookie, cookies)
+        };
+
+        for (cookie, inhibitor) in removed {
+            info!(cookie, %inhibitor, "Sender not connected, uninhibiting");
+            let _ = self.release(cookie, &inhibitor).await;
+        }
+        Ok(true)
+    }
+
+    /// Uninhibit everything that has outlived its maximum duration, returning the cookies of clients' inhibitors and
+    /// who held them. The bridge's own are left out, it has no one to tell.
+    pub async fn remove_expired(&self) -> anyhow::Result<Vec<(u32, UniqueName<'static>)>> {
+        let now = Instant::now();
+        let removed = {
+            let mut by_cookie = self.by_cookie.lock()
+                .map_err(|e| anyhow::anyhow!("Inhibitors map lock error: {:?}", e))?;
+            let cookies: Vec<u32> = by_cookie.iter()
+                .filter(|(_, x)| x.expires.is_some_and(|x| x <= now))
+                .map(|(x, _)| *x)
+                .collect();
+            self.remove_all(&mut by_cookie, cookies)
+        };
+
+        let mut expired = Vec::with_capacity(removed.len());
+        for (cookie, inhibitor) in removed {
+            info!(cookie, %inhibitor, "Inhibitor reached its maximum duration, uninhibiting");
+            let _ = self.release(cookie, &inhibitor).await;
+            if !matches!(inhibitor.origin, Origin::Bridge(_)) {
+                expired.push((cookie, inhibitor.sender));
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Uninhibit everything, for shutting down.
+    pub async fn clear(&self) {
+        let removed = {
+            let mut by_cookie = self.by_cookie.lock()
+                .expect("Could not obtain lock on inhibitors map for clean up");
+            let cookies: Vec<u32> = by_cookie.keys().copied().collect();
+            self.remove_all(&mut by_cookie, cookies)
+        };
+
+        for (cookie, inhibitor) in removed {
+            info!(cookie, %inhibitor, "Uninhibiting");
+            let _ = self.release(cookie, &inhibitor).await;
+        }
+    }
+
+    fn reserve(&self) -> Result<u32, String> {
+        let by_cookie = self.by_cookie.lock().map_err(|e| format!("{:?}", e))?;
+        let mut reserved = self.reserved.lock().map_err(|e| format!("{:?}", e))?;
+        // find an insert a new cookie. we're locked so this should be gucci
+        let cookie = loop {
+            let cookie = fastrand::u32(..);
+            if !by_cookie.contains_key(&cookie) && !reserved.contains(&cookie) {
+                break cookie;
+            }
+        };
+        reserved.insert(cookie);
+        Ok(cookie)
+    }
+
+    fn unreserve(&self, cookie: u32) {
+        if let Ok(mut reserved) = self.reserved.lock() {
+            reserved.remove(&cookie);
+        }
+    }
+
+    fn insert(&self, cookie: u32, inhibitor: StoredInhibitor) -> Result<(), String> {
+        let mut by_cookie = self.by_cookie.lock().map_err(|e| format!("{:?}", e))?;
+        by_cookie.insert(cookie, inhibitor);
+        self.unreserve(cookie);
+        self.update_inhibited(&by_cookie);
+        // No receivers is fine.
+        let _ = self.events.send(InhibitorEvent::Added(cookie));
+        Ok(())
+    }
+
+    fn remove_all(
+        &self,
+        by_cookie: &mut HashMap<u32, StoredInhibitor>,
+        cookies: Vec<u32>,
+    ) -> Vec<(u32, StoredInhibitor)> {
+        let removed: Vec<_> = cookies.into_iter()
+            .filter_map(|x| by_cookie.remove(&x).map(|y| (x, y)))
+            .collect();
+        for (cookie, _) in &removed {
+            self.removed(*cookie, by_cookie);
+        }
+        removed
+    }
+
+    /// Release the inhibitor from every backend that acquired it, trying all of them even if some fail.
+    async fn release(&self, cookie: u32, inhibitor: &StoredInhibitor) -> anyhow::Result<()> {
+        let mut failed = Vec::new();
+        for backend in self.backends.iter().filter(|x| inhibitor.backends.contains(&x.name())) {
+            if let Err(e) = backend.release(cookie).await {
+                error!(cookie, backend=backend.name(), error=?e, "Failed to release inhibitor");
+                failed.push(format!("{}: {:?}", backend.name(), e));
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!(failed.join(", "))
+        }
+    }
+
+    fn removed(&self, cookie: u32, by_cookie: &HashMap<u32, StoredInhibitor>) {
+        self.update_inhibited(by_cookie);
+        // No receivers is fine.
+        let _ = self.events.send(InhibitorEvent::Removed(cookie));
+    }
+
+    fn update_inhibited(&self, by_cookie: &HashMap<u32, StoredInhibitor>) {
+        self.inhibited.send_if_modified(|x| {
+            let old = std::mem::replace(x, !by_cookie.is_empty());
+            old != *x
+        });
+    }
+}
+
+/// What inhibiting needs to ask the bus about senders.
+#[async_trait]
+pub(crate) trait Bus: Sync {
+    /// The process behind `sender`.
+    async fn process(&self, sender: &UniqueName<'_>) -> fdo::Result<Process>;
+
+    /// Whether `name` is still connected to the bus, assuming it is if the bus won't say.
+    async fn is_connected(&self, name: &UniqueName<'_>) -> bool;
+}
+
+#[async_trait]
+impl Bus for zbus::Connection {
+    async fn process(&self, sender: &UniqueName<'_>) -> fdo::Result<Process> {
+        Process::of_sender(self, sender).await
+    }
+
+    async fn is_connected(&self, name: &UniqueName<'_>) -> bool {
+        let result = async {
+            fdo::DBusProxy::new(self).await?.name_has_owner(name.as_ref().into()).await
+        }.await;
+        result.unwrap_or_else(|e| {
+            warn!(error=?e, %name, "Unable to tell whether the sender is still connected");
+            true
+        })
+    }
+}
+
+/// An inhibitor held by the bridge itself while some condition holds, e.g. a window being fullscreen. Shows up in the
+/// inhibitors map like any other, with our own unique name as the sender.
+#[derive(Debug)]
+pub(crate) struct Synthetic {
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+    application_name: &'static str,
+    cookie: Option<u32>,
+}
+
+impl Synthetic {
+    pub fn new(inhibitors: Inhibitors, connection: zbus::Connection, application_name: &'static str) -> Self {
+        Self {
+            inhibitors,
+            connection,
+            application_name,
+            cookie: None,
+        }
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.cookie.is_some_and(|x| self.inhibitors.contains(x))
+    }
+
+    /// Acquire the inhibitor if `active` and not already held, or release it if not `active`.
+    pub async fn set(&mut self, active: bool, reason: &str) -> fdo::Result<()> {
+        // Released through the control interface in the mean time.
+        if self.cookie.is_some_and(|x| !self.inhibitors.contains(x)) {
+            self.cookie = None;
+        }
+
+        match (active, self.cookie) {
+            (true, None) => {
+                let Some(sender) = self.connection.unique_name().map(|x| x.to_owned().into_inner()) else {
+                    return Err(fdo::Error::Failed("No unique name on the connection".to_string()));
+                };
+                // Zero exempts it from the maximum duration, it's released once the condition no longer holds.
+                self.cookie = Some(self.inhibitors.inhibit(
+                    &self.connection,
+                    sender,
+                    self.application_name,
+                    reason,
+                    InhibitFlags::IDLE,
+                    Origin::Bridge(Duration::ZERO),
+                ).await?);
+            },
+            (false, Some(cookie)) => {
+                self.cookie = None;
+                self.inhibitors.uninhibit(cookie).await?;
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[derive(Debug)]
+    struct FakeBackend {
+        name: &'static str,
+        flags: InhibitFlags,
+        fail: bool,
+        held: Mutex<HashSet<u32>>,
+        released: Mutex<Vec<u32>>,
+    }
+
+    impl FakeBackend {
+        fn new(name: &'static str, flags: InhibitFlags, fail: bool) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                flags,
+                fail,
+                held: Mutex::new(HashSet::new()),
+                released: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn held(&self) -> HashSet<u32> {
+            self.held.lock().unwrap().clone()
+        }
+
+        fn released(&self) -> Vec<u32> {
+            self.released.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Backend for Arc<FakeBackend> {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn flags(&self) -> InhibitFlags {
+            self.flags
+        }
+
+        async fn acquire(&self, request: &Request<'_>) -> anyhow::Result<()> {
+            anyhow::ensure!(!self.fail, "{} failed", self.name);
+            self.held.lock().unwrap().insert(request.cookie);
+            Ok(())
+        }
+
+        async fn release(&self, cookie: u32) -> anyhow::Result<()> {
+            self.held.lock().unwrap().remove(&cookie);
+            self.released.lock().unwrap().push(cookie);
+            Ok(())
+        }
+
+        async fn health(&self) -> Health {
+            Health::Healthy
+        }
+    }
+
+    struct FakeBus {
+        connected: bool,
+    }
+
+    #[async_trait]
+    impl Bus for FakeBus {
+        async fn process(&self, _sender: &UniqueName<'_>) -> fdo::Result<Process> {
+            Err(fdo::Error::Failed("no processes here".to_string()))
+        }
+
+        async fn is_connected(&self, _name: &UniqueName<'_>) -> bool {
+            self.connected
+        }
+    }
+
+    const CONNECTED: FakeBus = FakeBus { connected: true };
+
+    fn sender() -> UniqueName<'static> {
+        UniqueName::try_from(":1.42").unwrap()
+    }
+
+    fn inhibitors(backends: &[&Arc<FakeBackend>], policy: &str) -> Inhibitors {
+        let backends = backends.iter().map(|x| Box::new(Arc::clone(x)) as Box<dyn Backend>).collect();
+        Inhibitors::new(backends, Config::parse(policy).unwrap().policy)
+    }
+
+    async fn inhibit(inhibitors: &Inhibitors, flags: InhibitFlags, origin: Origin) -> fdo::Result<u32> {
+        inhibitors.inhibit(&CONNECTED, sender(), "app", "reason", flags, origin).await
+    }
+
+    #[tokio::test]
+    async fn partial_success() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, false);
+        let b = FakeBackend::new("b", InhibitFlags::IDLE, true);
+        let inhibitors = inhibitors(&[&a, &b], "");
+        let cookie = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.unwrap();
+        assert!(inhibitors.is_inhibited());
+        assert_eq!(inhibitors.get(cookie).unwrap().backends, vec!["a"]);
+        assert_eq!(a.held(), HashSet::from([cookie]));
+
+        // Only the backends that acquired it release it.
+        inhibitors.uninhibit(cookie).await.unwrap();
+        assert!(!inhibitors.is_inhibited());
+        assert_eq!(a.released(), vec![cookie]);
+        assert!(b.released().is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_failed() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, true);
+        let inhibitors = inhibitors(&[&a], "");
+        assert!(inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.is_err());
+        assert!(!inhibitors.is_inhibited());
+        assert!(inhibitors.list().unwrap().is_empty());
+        assert!(inhibitors.reserved.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_backends_with_the_flags() {
+        let idle = FakeBackend::new("idle", InhibitFlags::IDLE, false);
+        let suspend = FakeBackend::new("suspend", InhibitFlags::SUSPEND, true);
+        let inhibitors = inhibitors(&[&idle, &suspend], "");
+        // The failing one isn't even asked.
+        let cookie = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.unwrap();
+        assert_eq!(inhibitors.get(cookie).unwrap().backends, vec!["idle"]);
+
+        // Nothing to ask is still a success, just a useless one.
+        let cookie = inhibit(&inhibitors, InhibitFlags::AUTOMOUNT, Origin::Client).await.unwrap();
+        assert!(inhibitors.get(cookie).unwrap().backends.is_empty());
+    }
+
+    #[tokio::test]
+    async fn denied() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, false);
+        let inhibitors = inhibitors(&[&a], "[policy]\ndefault = \"deny\"");
+        let e = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.unwrap_err();
+        assert!(matches!(e, fdo::Error::AccessDenied(_)));
+        assert!(a.held().is_empty());
+        // The bridge's own are exempt.
+        inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Bridge(Duration::ZERO)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sender_disconnected() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, false);
+        let inhibitors = inhibitors(&[&a], "");
+        let bus = FakeBus { connected: false };
+        assert!(inhibitors.inhibit(&bus, sender(), "app", "", InhibitFlags::IDLE, Origin::Client).await.is_err());
+        assert!(!inhibitors.is_inhibited());
+        assert!(a.held().is_empty());
+        assert_eq!(a.released().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_max_duration_means_no_limit() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, false);
+        let inhibitors = inhibitors(&[&a], r#"
+            [policy]
+            max_duration = 60
+
+            [[policy.rule]]
+            application_name = "app"
+            action = "allow"
+            max_duration = 0
+        "#);
+        let cookie = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.unwrap();
+        assert_eq!(inhibitors.get(cookie).unwrap().expires, None);
+        let cookie = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Bridge(Duration::ZERO)).await.unwrap();
+        assert_eq!(inhibitors.get(cookie).unwrap().expires, None);
+        assert_eq!(inhibitors.next_expiry(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn remove_expired() {
+        let a = FakeBackend::new("a", InhibitFlags::IDLE, false);
+        let inhibitors = inhibitors(&[&a], "[policy]\nmax_duration = 60");
+        let client = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Client).await.unwrap();
+        let bridge = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Bridge(Duration::from_secs(30))).await.unwrap();
+        let forever = inhibit(&inhibitors, InhibitFlags::IDLE, Origin::Bridge(Duration::ZERO)).await.unwrap();
+        assert!(inhibitors.remove_expired().await.unwrap().is_empty());
+
+        // The bridge's own expires, but there's no one to tell.
+        time::advance(Duration::from_secs(30)).await;
+        assert!(inhibitors.remove_expired().await.unwrap().is_empty());
+        assert!(!inhibitors.contains(bridge));
+        assert_eq!(a.released(), vec![bridge]);
+
+        // Extended ones are kept for longer.
+        assert!(inhibitors.extend(client, Duration::from_secs(60)));
+        time::advance(Duration::from_secs(30)).await;
+        assert!(inhibitors.remove_expired().await.unwrap().is_empty());
+        time::advance(Duration::from_secs(30)).await;
+        assert_eq!(inhibitors.remove_expired().await.unwrap(), vec![(client, sender())]);
+        assert!(inhibitors.contains(forever));
+        assert!(!inhibitors.extend(client, Duration::from_secs(60)));
+    }
+}
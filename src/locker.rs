@@ -0,0 +1,84 @@
+// Locking the screen with a locker command of our own choosing, e.g. swaylock, rather than through systemd-logind.
+use std::process::Stdio;
+use std::time::Instant;
+
+use anyhow::Context as _;
+use tokio::process::Command;
+use tokio::sync::watch;
+use tracing::{error, info, trace};
+
+/// Runs the locker, tracking whether it's still running so that GetActive and GetActiveTime can tell.
+#[derive(Debug, Clone)]
+pub(crate) struct Locker {
+    command: Vec<String>,
+    /// When the locker was started, None while it's not running.
+    locked: watch::Sender<Option<Instant>>,
+}
+
+impl Locker {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            locked: watch::Sender::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Instant>> {
+        self.locked.subscribe()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.borrow().is_some()
+    }
+
+    /// When the running locker was started, if it's running.
+    pub fn locked_since(&self) -> Option<Instant> {
+        *self.locked.borrow()
+    }
+
+    /// Start the locker, unless it's already running. Only the start can fail, the locker exiting later on is
+    /// only logged.
+    pub fn lock(&self) -> anyhow::Result<()> {
+        let Some((program, args)) = self.command.split_first() else {
+            anyhow::bail!("No locker command");
+        };
+        // Claim the lock first, so that concurrent calls don't start a locker each.
+        let claimed = self.locked.send_if_modified(|x| {
+            let free = x.is_none();
+            if free {
+                *x = Some(Instant::now());
+            }
+            free
+        });
+        if !claimed {
+            trace!("Locker already running");
+            return Ok(());
+        }
+
+        let child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .spawn()
+            .with_context(|| format!("running {}", program));
+        let mut child = match child {
+            Ok(x) => x,
+            Err(e) => {
+                self.locked.send_replace(None);
+                return Err(e);
+            },
+        };
+        info!(pid=child.id(), program, "Started locker");
+
+        let locked = self.locked.clone();
+        let program = program.clone();
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => info!(program, "Locker exited, unlocked"),
+                Ok(status) => error!(program, %status, "Locker failed"),
+                Err(e) => error!(program, error=?e, "Waiting for the locker failed"),
+            }
+            locked.send_replace(None);
+        });
+        Ok(())
+    }
+}
@@ -1,31 +1,49 @@
 #![forbid(unsafe_code)]
 // Bridge between the org.freedesktop.ScreenSaver interface and either the Wayland idle
 // inhibitor protocol or systemd-logind D-Bus interface (org.freedesktop.login1).
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashSet};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use argh::FromArgs;
 use anyhow::Context as _;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, watch};
 use tokio::time::{self, Duration};
 use tokio_stream::wrappers::{IntervalStream, WatchStream};
 use futures_util::stream::{BoxStream, SelectAll, StreamExt};
-use tracing::{error, info, instrument, trace};
+use tracing::{error, info, instrument, trace, warn};
 use tracing_subscriber::EnvFilter;
 use zbus::message::Header;
 use zbus::names::UniqueName;
 use zbus::fdo;
+use zbus::object_server::SignalEmitter;
 use zbus_macros::interface;
 #[cfg(feature = "wayland")]
-use {
-    wayland_protocols::wp::idle_inhibit::zv1::client::zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
-    crate::wayland::InhibitorManager,
-};
+use crate::wayland::WaylandBackend;
 #[cfg(feature = "systemd")]
-use {
-    zbus::zvariant,
-    crate::xdg_login1::Login1Client,
-};
+use crate::xdg_login1::{InhibitMapping, Login1Backend, Login1Client};
+use crate::backend::{Backend, Health};
+use crate::config::Config;
+use crate::control::IoGithubWscreensaverBridgeControlServer;
+use crate::inhibitor::{InhibitFlags, Inhibitors, Origin};
+use crate::locker::Locker;
+use crate::gnome_session::OrgGnomeSessionManagerServer;
+use crate::portal::OrgFreedesktopImplPortalInhibitServer;
+use crate::power_management::OrgFreedesktopPowerManagementInhibitServer;
+
+mod audio;
+mod backend;
+mod client;
+mod config;
+mod control;
+mod gnome_session;
+mod inhibitor;
+mod locker;
+mod mpris;
+mod portal;
+mod power_management;
+mod process;
 
 #[cfg(feature = "wayland")]
 mod wayland;
@@ -33,52 +51,42 @@ mod wayland;
 #[cfg(feature = "systemd")]
 mod xdg_login1;
 
-#[derive(Debug)]
-struct StoredInhibitor {
-    #[cfg(feature = "wayland")]
-    inhibitor: ZwpIdleInhibitorV1,
-    sender: UniqueName<'static>,
-    #[cfg(feature = "systemd")]
-    /// org.freedesktop.login1 inhibitor lock, should uninhibit on drop.
-    _fd: zvariant::OwnedFd
-}
-
-#[derive(Debug)]
+/// Object paths clients are known to call org.freedesktop.ScreenSaver on.
+const SCREENSAVER_PATHS: &[&str] = &[
+    "/org/freedesktop/ScreenSaver",
+    // Chromium, Electron and some KDE tooling.
+    "/ScreenSaver",
+    // Older GNOME applications.
+    "/org/gnome/ScreenSaver",
+];
+
+/// The same server is served at every path in [`SCREENSAVER_PATHS`], so all state must be shared between clones.
+#[derive(Debug, Clone)]
 struct OrgFreedesktopScreenSaverServer {
     #[cfg(feature = "systemd")]
     login1: Login1Client,
-    #[cfg(feature = "wayland")]
-    inhibit_manager: Arc<InhibitorManager>,
-    // NOTE: Must not be held across await points.
-    inhibitors_by_cookie: Arc<Mutex<HashMap<u32, StoredInhibitor>>>,
-}
-
-impl OrgFreedesktopScreenSaverServer {
-    fn insert_inhibitor(&self, inhibitor: StoredInhibitor) -> Result<u32, String> {
-        // find an insert a new cookie. we're locked so this should be gucci
-        let cookie = loop {
-            let cookie = fastrand::u32(..);
-            if !self.inhibitors_by_cookie
-                .lock().map_err(|e| format!("{:?}", e))?
-                .contains_key(&cookie)
-            {
-                break cookie;
-            }
-        };
-        self.inhibitors_by_cookie
-            .lock().map_err(|e| format!("{:?}", e))?
-            .insert(cookie, inhibitor);
-        Ok(cookie)
-    }
+    inhibitors: Inhibitors,
+    /// When the session went idle, None while it's not or when idle isn't tracked.
+    idle: watch::Receiver<Option<Instant>>,
+    idle_timeout: Duration,
+    /// How long SimulateUserActivity inhibits for.
+    activity_hold: Duration,
+    /// The inhibitor held for SimulateUserActivity, if any. Held across inhibiting so that concurrent calls end up
+    /// with the one inhibitor.
+    activity: Arc<tokio::sync::Mutex<Option<u32>>>,
+    /// Locks the screen instead of systemd-logind, if configured.
+    locker: Option<Locker>,
 }
 
 #[interface(name = "org.freedesktop.ScreenSaver")]
 impl OrgFreedesktopScreenSaverServer {
-    #[instrument(skip(self, hdr), fields(sender=?hdr.sender()))]
+    #[instrument(skip(self, hdr, connection), fields(sender=?hdr.sender()))]
     async fn inhibit(
         &self,
         #[zbus(header)]
         hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
         application_name: String,
         reason_for_inhibit: String,
     ) -> fdo::Result<u32> {
@@ -88,72 +96,138 @@ impl OrgFreedesktopScreenSaverServer {
             return Err(fdo::Error::Failed(msg.to_string()));
         };
 
-        #[cfg(feature = "wayland")]
-        let inhibitor = self.inhibit_manager.create_inhibitor()
-            .map_err(|e| {
-                error!(error=?e, "Failed to create Wayland inhibitor");
-                fdo::Error::Failed(format!("Failed to create inhibitor: {:?}", e))
-            })?;
+        self.inhibitors.inhibit(connection, sender, &application_name, &reason_for_inhibit, InhibitFlags::IDLE, Origin::Client).await
+    }
 
-        #[cfg(feature = "systemd")]
-        let fd = self.login1.inhibit_idle(
-            env!("CARGO_PKG_NAME"),
-            &format!("{} {}", application_name, reason_for_inhibit)
-        ).await.map_err(|e| {
-            error!(error=?e, "Failed to create systemd-logind inhibitor");
-            e
-        })?;
+    #[instrument(skip(self, hdr), fields(uninhibit_sender=?hdr.sender()))]
+    async fn un_inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        cookie: u32
+    ) -> fdo::Result<()> {
+        self.inhibitors.uninhibit(cookie).await
+    }
 
-        let cookie = self.insert_inhibitor(StoredInhibitor {
-            sender,
-            #[cfg(feature = "wayland")]
-            inhibitor,
-            #[cfg(feature = "systemd")]
-            _fd: fd,
-        }).map_err(|e| {
-            error!(error=?e, "Unable to retain the inhibitor");
-            fdo::Error::Failed(format!("Unable to retain the inhibitor: {}", e))
-        })?;
+    /// There's no screensaver as such, so it's active while the session is idle or the locker is running.
+    #[instrument(skip(self))]
+    async fn get_active(&self) -> bool {
+        self.idle.borrow().is_some() || self.locker.as_ref().is_some_and(Locker::is_locked)
+    }
 
-        info!(cookie, "Inhibiting screensaver for {} because {}.", application_name, reason_for_inhibit);
+    /// We have no screensaver to activate, so always report that the request was not honored.
+    #[instrument(skip(self))]
+    async fn set_active(&self, active: bool) -> bool {
+        info!("Ignoring request to change the screensaver state");
+        false
+    }
 
-        Ok(cookie)
+    /// Seconds the screensaver has been active for, since the session went idle or the locker started, whichever
+    /// came first.
+    #[instrument(skip(self))]
+    async fn get_active_time(&self) -> u32 {
+        let idle = *self.idle.borrow();
+        let locked = self.locker.as_ref().and_then(Locker::locked_since);
+        idle.into_iter().chain(locked).min().map_or(0, |x| secs(x.elapsed()))
     }
 
-    #[instrument(skip(self, hdr), fields(uninhibit_sender=?hdr.sender()))]
-    async fn un_inhibit(
+    /// Seconds the session has been idle for. Only known once it has been idle for the timeout, 0 until then.
+    #[instrument(skip(self))]
+    async fn get_session_idle_time(&self) -> u32 {
+        self.idle.borrow().map_or(0, |x| secs(x.elapsed() + self.idle_timeout))
+    }
+
+    /// Wayland has no way to simulate activity, but compositors reset their idle timers when an inhibitor is
+    /// created or destroyed. So create one, and either destroy it right away or hold it for a while. Calls while it's
+    /// held extend it instead, each starting the window over.
+    #[instrument(skip(self, hdr, connection), fields(sender=?hdr.sender()))]
+    async fn simulate_user_activity(
         &self,
         #[zbus(header)]
         hdr: Header<'_>,
-        cookie: u32
+        #[zbus(connection)]
+        connection: &zbus::Connection,
     ) -> fdo::Result<()> {
-        let mut inhibitors_by_cookie = self.inhibitors_by_cookie.lock()
-            .map_err(|e| {
-                error!(error=?e, "Could not obtain lock for inhibitors map");
-                fdo::Error::Failed(format!("Could not obtain lock on inhibitors map for clean up: {:?}", e))
-            })?;
-        match inhibitors_by_cookie.entry(cookie) {
-            std::collections::hash_map::Entry::Occupied(e) => {
-                info!(inhibit_sender=?e.get().sender, "Uninhibiting");
-                let _inhibitor = e.remove();
-
-                #[cfg(feature = "wayland")]
-                match self.inhibit_manager.destroy_inhibitor(_inhibitor.inhibitor) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!(error=?e, "Failed to destroy inhibitor");
-                        return Err(fdo::Error::Failed(format!("Failed to destroy inhibitor: {:?}", e)));
-                    }
-                };
+        let Some(own_name) = connection.unique_name().map(|x| x.to_owned().into_inner()) else {
+            return Err(fdo::Error::Failed("No unique name on the connection".to_string()));
+        };
+        let reason = format!("User activity simulated by {}", hdr.sender().map_or("unknown".to_string(), |x| x.to_string()));
 
-                Ok(())
-            },
-            std::collections::hash_map::Entry::Vacant(_) => {
-                error!("Cookie not found");
-                Err(fdo::Error::Failed(format!("No inhibitor with cookie {}", cookie)))
-            },
+        let mut activity = self.activity.lock().await;
+        if let Some(cookie) = activity.filter(|x| self.inhibitors.extend(*x, self.activity_hold)) {
+            trace!(cookie, hold=?self.activity_hold, "Extending user activity inhibitor");
+            return Ok(())
+        }
+
+        // Zero would mean no limit, but then it's released right away anyway.
+        let cookie = self.inhibitors.inhibit(
+            connection,
+            own_name,
+            "User activity",
+            &reason,
+            InhibitFlags::IDLE,
+            Origin::Bridge(self.activity_hold),
+        ).await?;
+        if self.activity_hold.is_zero() {
+            trace!(cookie, "Releasing user activity inhibitor right away");
+            self.inhibitors.uninhibit(cookie).await
+        } else {
+            trace!(cookie, hold=?self.activity_hold, "Holding user activity inhibitor");
+            *activity = Some(cookie);
+            Ok(())
+        }
+    }
+
+    /// Run the configured locker, or otherwise lock the caller's session through systemd-logind. Not supported
+    /// without either.
+    #[instrument(skip(self, hdr, connection), fields(sender=?hdr.sender()))]
+    async fn lock(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
+    ) -> fdo::Result<()> {
+        if let Some(locker) = &self.locker {
+            info!("Locking with the locker command");
+            return locker.lock().map_err(|e| {
+                error!(error=?e, "Failed to start the locker");
+                fdo::Error::Failed(format!("{:#}", e))
+            });
+        }
+
+        #[cfg(feature = "systemd")]
+        {
+            let Some(sender) = hdr.sender() else {
+                let msg = "No sender provided";
+                error!(msg);
+                return Err(fdo::Error::Failed(msg.to_string()));
+            };
+            let process = process::Process::of_sender(connection, sender).await?;
+            let Some(uid) = process.uid else {
+                return Err(fdo::Error::Failed(format!("Unable to tell the user of {}", sender)));
+            };
+            info!(%process, uid, "Locking the caller's session");
+            self.login1.lock_session_of(process.pid, uid).await.map_err(|e| {
+                error!(error=?e, "Failed to lock the session");
+                e
+            })
+        }
+
+        #[cfg(not(feature = "systemd"))]
+        {
+            let _ = connection;
+            error!("No locker command nor backend to lock the session with");
+            Err(fdo::Error::NotSupported("Locking requires a locker command or the systemd feature".to_string()))
         }
     }
+
+    #[zbus(signal)]
+    async fn active_changed(emitter: &SignalEmitter<'_>, new_value: bool) -> zbus::Result<()>;
+}
+
+fn secs(x: Duration) -> u32 {
+    u32::try_from(x.as_secs()).unwrap_or(u32::MAX)
 }
 
 /// A bridge between org.freedesktop.ScreenSaver and Wayland's or systemd-logind's idle inhibit.
@@ -162,10 +236,54 @@ struct Args {
     /// set logging level (default: info)
     #[argh(option, default="tracing::Level::INFO")]
     log_level: tracing::Level,
+    /// path to the config file (default: $XDG_CONFIG_HOME/wscreensaver-bridge/config.toml)
+    #[argh(option)]
+    config: Option<PathBuf>,
     /// provide an interval in seconds to poll for active inhibitors using D-Bus ListNames, instead of
     /// listening for D-Bus NameOwnerChanged signals
     #[argh(option)]
     heartbeat_interval: Option<u64>,
+    /// object path to serve org.freedesktop.ScreenSaver at, can be given multiple times (default:
+    /// /org/freedesktop/ScreenSaver, /ScreenSaver and /org/gnome/ScreenSaver)
+    #[argh(option)]
+    path: Vec<String>,
+    /// don't serve org.freedesktop.PowerManagement.Inhibit, e.g. when another daemon already owns the name
+    #[argh(switch)]
+    no_power_management: bool,
+    /// also serve the inhibit methods of org.gnome.SessionManager, for gtk_application_inhibit
+    #[argh(switch)]
+    gnome_session_manager: bool,
+    /// also act as an xdg-desktop-portal backend for org.freedesktop.impl.portal.Inhibit
+    #[argh(switch)]
+    portal: bool,
+    /// backend to inhibit with, can be given multiple times (default: all of wayland and logind that were compiled
+    /// in)
+    #[argh(option)]
+    backend: Vec<String>,
+    /// seconds to wait for the Wayland compositor to show up, e.g. when started before it (default: 10)
+    #[cfg(feature = "wayland")]
+    #[argh(option, default = "10")]
+    wait_for_display: u64,
+    /// what to attach the Wayland inhibitor to, hidden, layer-shell or toplevel, for compositors that only honor
+    /// inhibitors on visible surfaces (default: hidden)
+    #[cfg(feature = "wayland")]
+    #[argh(option, default = "Default::default()")]
+    inhibit_surface: wayland::SurfaceMode,
+    /// systemd-logind lock to take when asked to inhibit suspend, can be given multiple times (default: sleep)
+    #[cfg(feature = "systemd")]
+    #[argh(option)]
+    suspend_lock: Vec<xdg_login1::What>,
+    /// systemd-logind lock to take when asked to inhibit logout, can be given multiple times (default: shutdown)
+    #[cfg(feature = "systemd")]
+    #[argh(option)]
+    logout_lock: Vec<xdg_login1::What>,
+    /// block or delay, the mode of the systemd-logind sleep and shutdown locks (default: block)
+    #[cfg(feature = "systemd")]
+    #[argh(option, default = "Default::default()")]
+    lock_mode: xdg_login1::Mode,
+    /// talk to the running daemon instead of being one
+    #[argh(subcommand)]
+    command: Option<client::Command>,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -181,6 +299,10 @@ pub async fn main() -> anyhow::Result<()> {
         .compact()
         .init();
 
+    if let Some(command) = args.command {
+        return client::run(command).await;
+    }
+
     let (terminator_tx, mut terminator_rx) = watch::channel(false);
     let heartbeat_terminator = terminator_tx.subscribe();
     let terminator = terminator_tx.clone();
@@ -192,46 +314,263 @@ pub async fn main() -> anyhow::Result<()> {
 
     info!("Starting screensaver bridge");
 
+    let config = Config::load(args.config.as_deref())?;
+
+    let available = [
+        #[cfg(feature = "wayland")]
+        wayland::NAME,
+        #[cfg(feature = "systemd")]
+        xdg_login1::NAME,
+    ];
+    if let Some(x) = args.backend.iter().find(|x| !available.contains(&x.as_str())) {
+        anyhow::bail!("Unknown backend {:?}, available backends are: {}", x, available.join(", "));
+    }
+    #[cfg(any(feature = "wayland", feature = "systemd"))]
+    let enabled = |name: &str| args.backend.is_empty() || args.backend.iter().any(|x| x == name);
+    #[cfg_attr(not(any(feature = "wayland", feature = "systemd")), allow(unused_mut))]
+    let mut backends: Vec<Box<dyn Backend>> = Vec::new();
+
+    // Terminated separately, only after the inhibitors have been cleared on the way out.
     #[cfg(feature = "wayland")]
-    info!("Waiting for wayland compositor");
+    let (wayland_terminator_tx, wayland_terminator_rx) = watch::channel(false);
     #[cfg(feature = "wayland")]
-    let inhibit_manager = Arc::new(wayland::get_inhibit_manager().await?);
+    let wayland_handle = if enabled(wayland::NAME) {
+        info!("Waiting for wayland compositor");
+        match WaylandBackend::connect(Duration::from_secs(args.wait_for_display), args.inhibit_surface).await {
+            Ok((backend, driver)) => {
+                backends.push(Box::new(backend));
+                Some(tokio::spawn(driver.run(wayland_terminator_rx)))
+            },
+            // Only fall back when the backends weren't chosen explicitly, and there's something to fall back to.
+            Err(e) if args.backend.is_empty() && available.len() > 1 => {
+                warn!(error=?e, "Unable to use the Wayland backend, continuing without it");
+                None
+            },
+            Err(e) => return Err(e.context("Unable to use the Wayland backend")),
+        }
+    } else {
+        None
+    };
+
+    #[cfg(feature = "systemd")]
+    let login1 = {
+        let mut mapping = InhibitMapping {
+            mode: args.lock_mode,
+            ..Default::default()
+        };
+        if !args.suspend_lock.is_empty() {
+            mapping.suspend = args.suspend_lock;
+        }
+        if !args.logout_lock.is_empty() {
+            mapping.logout = args.logout_lock;
+        }
+        Login1Client::new(mapping).await?
+    };
+    #[cfg(feature = "systemd")]
+    if enabled(xdg_login1::NAME) {
+        backends.push(Box::new(Login1Backend::new(login1.clone())));
+    }
+
+    if backends.is_empty() {
+        warn!("No backends to inhibit with, inhibitors will be tracked but won't inhibit anything");
+    }
+    let inhibitors = Inhibitors::new(backends, config.policy);
+    for (backend, health) in inhibitors.health().await {
+        match health {
+            Health::Healthy => info!(backend, "Using backend"),
+            Health::Unhealthy(e) => warn!(backend, error=e, "Using backend, but it's unhealthy"),
+        }
+    }
+    // Never changes unless idle is tracked.
+    let (idle_tx, idle_rx) = watch::channel(None);
+    #[cfg(feature = "wayland")]
+    if config.idle.enabled {
+        wayland::spawn_idle_watcher(config.idle.timeout, idle_tx);
+    }
+    #[cfg(not(feature = "wayland"))]
+    {
+        drop(idle_tx);
+        if config.idle.enabled {
+            warn!("Tracking idle requires the wayland feature");
+        }
+    }
 
-    let inhibitors_by_cookie = Arc::new(Mutex::new(HashMap::new()));
     let screen_saver = OrgFreedesktopScreenSaverServer {
         #[cfg(feature = "systemd")]
-        login1: Login1Client::new().await?,
-        #[cfg(feature = "wayland")]
-        inhibit_manager: inhibit_manager.clone(),
-        inhibitors_by_cookie: inhibitors_by_cookie.clone(),
+        login1,
+        inhibitors: inhibitors.clone(),
+        idle: idle_rx.clone(),
+        idle_timeout: config.idle.timeout,
+        activity_hold: config.user_activity.hold,
+        activity: Arc::new(tokio::sync::Mutex::new(None)),
+        locker: (!config.lock.command.is_empty()).then(|| Locker::new(config.lock.command.clone())),
+    };
+    // Never changes without a locker.
+    let locked_rx = screen_saver.locker.as_ref().map_or_else(|| watch::channel(None).1, Locker::subscribe);
+
+    let paths = if args.path.is_empty() {
+        SCREENSAVER_PATHS.iter().map(|x| x.to_string()).collect()
+    } else {
+        args.path
     };
 
     info!("Starting ScreenSaver to Wayland bridge");
-    let connection = zbus::connection::Builder::session()?
+    let mut builder = zbus::connection::Builder::session()?
         .name("org.freedesktop.ScreenSaver")?
-        .serve_at("/org/freedesktop/ScreenSaver", screen_saver)?
-        .build().await?;
+        .name(control::NAME)?
+        .serve_at(control::PATH, IoGithubWscreensaverBridgeControlServer {
+            inhibitors: inhibitors.clone(),
+        })?;
+    for path in &paths {
+        info!(path, "Serving org.freedesktop.ScreenSaver");
+        builder = builder.serve_at(path.as_str(), screen_saver.clone())?;
+    }
+    let gnome_exported = Arc::new(Mutex::new(BTreeSet::new()));
+    if args.gnome_session_manager {
+        info!(path=gnome_session::PATH, "Serving org.gnome.SessionManager");
+        builder = builder
+            .name(gnome_session::NAME)?
+            .serve_at(gnome_session::PATH, OrgGnomeSessionManagerServer {
+                inhibitors: inhibitors.clone(),
+                exported: gnome_exported.clone(),
+            })?;
+    }
+    let portal_requests = portal::Requests::default();
+    if args.portal {
+        info!(path=portal::PATH, "Serving org.freedesktop.impl.portal.Inhibit");
+        builder = builder
+            .name(portal::NAME)?
+            .serve_at(portal::PATH, OrgFreedesktopImplPortalInhibitServer {
+                inhibitors: inhibitors.clone(),
+                requests: portal_requests.clone(),
+            })?;
+    }
+    let connection = builder.build().await?;
+
+    // Power managers such as xfce4-power-manager and PowerDevil own the name themselves, so it's not fatal if taken.
+    let power_management = !args.no_power_management && serve_power_management(&connection, &inhibitors).await?;
+
+    // NameOwnerChanged signals of media players, forwarded by the clean up task.
+    let (name_owner_changed_tx, name_owner_changed_rx) = broadcast::channel(16);
+    let mpris_handle = config.mpris.enabled.then(|| {
+        tokio::spawn(mpris::mpris_task(
+            terminator_tx.subscribe(),
+            name_owner_changed_rx,
+            inhibitors.clone(),
+            connection.clone(),
+            config.mpris.player.clone(),
+        ))
+    });
 
-    #[cfg(feature = "wayland")]
-    let inhibit_manager_ref = inhibit_manager.clone();
-    let inhibitors_ref = inhibitors_by_cookie.clone();
+    let inhibitors_ref = inhibitors.clone();
     let connection_ref = connection.clone();
     let cleanup_handle = tokio::spawn(async move {
         inhibitor_cleanup_task(
             args.heartbeat_interval,
             heartbeat_terminator,
-            #[cfg(feature = "wayland")]
-            inhibit_manager_ref,
             inhibitors_ref,
             connection_ref,
+            name_owner_changed_tx,
         ).await
     });
 
+    let expiry_handle = tokio::spawn(inhibitor_expiry_task(
+        terminator_tx.subscribe(),
+        inhibitors.clone(),
+        connection.clone(),
+    ));
+
+    let control_handle = tokio::spawn(control::signal_task(
+        terminator_tx.subscribe(),
+        inhibitors.clone(),
+        idle_rx.clone(),
+        connection.clone(),
+    ));
+
+    let active_changed_handle = tokio::spawn(active_changed_task(
+        terminator_tx.subscribe(),
+        idle_rx,
+        locked_rx,
+        paths,
+        connection.clone(),
+    ));
+
+    let has_inhibit_changed_handle = power_management.then(|| {
+        tokio::spawn(power_management::has_inhibit_changed_task(
+            terminator_tx.subscribe(),
+            inhibitors.clone(),
+            connection.clone(),
+        ))
+    });
+
+    let gnome_session_handle = args.gnome_session_manager.then(|| {
+        tokio::spawn(gnome_session::inhibitor_object_task(
+            terminator_tx.subscribe(),
+            inhibitors.clone(),
+            gnome_exported,
+            connection.clone(),
+        ))
+    });
+
+    #[cfg(feature = "wayland")]
+    let fullscreen_handle = config.fullscreen.enabled.then(|| {
+        tokio::spawn(wayland::fullscreen_task(
+            terminator_tx.subscribe(),
+            inhibitors.clone(),
+            connection.clone(),
+            config.fullscreen.app_id.clone(),
+        ))
+    });
+    #[cfg(not(feature = "wayland"))]
+    if config.fullscreen.enabled {
+        warn!("Inhibiting for fullscreen windows requires the wayland feature");
+    }
+
+    let audio_handle = config.audio.enabled.then(|| {
+        tokio::spawn(audio::audio_task(
+            terminator_tx.subscribe(),
+            inhibitors.clone(),
+            connection.clone(),
+            config.audio.clone(),
+        ))
+    });
+
+    let portal_handle = args.portal.then(|| {
+        tokio::spawn(portal::request_object_task(
+            terminator_tx.subscribe(),
+            inhibitors.clone(),
+            portal_requests,
+            connection.clone(),
+        ))
+    });
+
     // Run until SIGTERM/SIGHUP/SIGINT
     terminator_rx.changed().await?;
 
     // Clean up the inhibitor clean up task.
     cleanup_handle.await??;
+    expiry_handle.await??;
+    control_handle.await??;
+    active_changed_handle.await??;
+    if let Some(handle) = has_inhibit_changed_handle {
+        handle.await??;
+    }
+    if let Some(handle) = gnome_session_handle {
+        handle.await??;
+    }
+    if let Some(handle) = portal_handle {
+        handle.await??;
+    }
+    #[cfg(feature = "wayland")]
+    if let Some(handle) = fullscreen_handle {
+        handle.await??;
+    }
+    if let Some(handle) = audio_handle {
+        handle.await??;
+    }
+    if let Some(handle) = mpris_handle {
+        handle.await??;
+    }
 
     info!("Stopping screensaver bridge, cleaning up any left over inhibitors...");
     // This should also close the ObjectServer? We don't want to accept any new inhibitors no more.
@@ -239,35 +578,44 @@ pub async fn main() -> anyhow::Result<()> {
         error!(error=?e, "Error closing D-Bus connection");
     }
 
-    // org.freedesktop.login1 inhibitors get freed on drop, and thus require no clean up from us. But the Wayland
-    // idle-inhibit protocol requires that we explicitly destroy the inhibitors.
-    // TODO: Just write a wrapper for ZwpIdleInhibitorV1 that does this on drop?
+    inhibitors.clear().await;
+
     #[cfg(feature = "wayland")]
-    {
-        let mut inhibitors = inhibitors_by_cookie.lock()
-            .expect("Could not obtain lock on inhibitors map for clean up");
-        for (cookie, inhibitor) in inhibitors.drain() {
-            info!(cookie, "Uninhibiting");
-
-            match inhibit_manager.destroy_inhibitor(inhibitor.inhibitor.clone()) {
-                Ok(_) => (),
-                Err(e) => {
-                    error!(cookie, error=?e, "Failed to destroy Wayland inhibitor");
-                }
-            }
-        }
+    if let Some(handle) = wayland_handle {
+        wayland_terminator_tx.send(true)?;
+        handle.await??;
     }
 
     Ok(())
 }
 
+/// Serve org.freedesktop.PowerManagement.Inhibit, unless another daemon already owns the name. Returns whether it's
+/// served.
+async fn serve_power_management(connection: &zbus::Connection, inhibitors: &Inhibitors) -> anyhow::Result<bool> {
+    let object_server = connection.object_server();
+    object_server.at(power_management::PATH, OrgFreedesktopPowerManagementInhibitServer {
+        inhibitors: inhibitors.clone(),
+    }).await?;
+    match connection.request_name(power_management::NAME).await {
+        Ok(()) => {
+            info!(path=power_management::PATH, "Serving org.freedesktop.PowerManagement.Inhibit");
+            Ok(true)
+        },
+        Err(zbus::Error::NameTaken) => {
+            warn!(name=power_management::NAME, "Name already owned by another daemon, not serving org.freedesktop.PowerManagement.Inhibit");
+            object_server.remove::<OrgFreedesktopPowerManagementInhibitServer, _>(power_management::PATH).await?;
+            Ok(false)
+        },
+        Err(e) => Err(e).context("requesting org.freedesktop.PowerManagement"),
+    }
+}
+
 async fn inhibitor_cleanup_task(
     heartbeat_interval: Option<u64>,
     terminator: watch::Receiver<bool>,
-    #[cfg(feature = "wayland")]
-    inhibit_manager: Arc<InhibitorManager>,
-    inhibitors_by_cookie: Arc<Mutex<HashMap<u32, StoredInhibitor>>>,
-    connection: zbus::Connection
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+    name_owner_changed: broadcast::Sender<fdo::NameOwnerChanged>,
 ) -> anyhow::Result<()> {
     info!("Starting inhibitor clean up task");
     let proxy = fdo::DBusProxy::new(&connection).await?;
@@ -283,7 +631,9 @@ async fn inhibitor_cleanup_task(
 
     if let Some(interval) = heartbeat_interval {
         stream.push(Box::pin(IntervalStream::new(time::interval(Duration::from_secs(interval))).map(Message::Interval)));
-    } else {
+    }
+    // Still needed with the heartbeat when the MPRIS task is listening.
+    if heartbeat_interval.is_none() || name_owner_changed.receiver_count() > 0 {
         stream.push(Box::pin(proxy.receive_name_owner_changed().await?.map(Message::NameOwnerChanged)));
     }
 
@@ -297,7 +647,7 @@ async fn inhibitor_cleanup_task(
             Message::Interval(_x) => {
                 // Shamelessly copied from https://github.com/bdwalton/inhibit-bridge, try to make sure we don't leave
                 // any stale inhibitors active.
-                if inhibitors_by_cookie.try_lock().is_ok_and(|xs| !xs.is_empty()) {
+                if inhibitors.is_inhibited() {
                     let names: HashSet<UniqueName<'static>> = proxy.list_names().await?
                         .into_iter()
                         .filter_map(|x| match x.into_inner() {
@@ -306,29 +656,15 @@ async fn inhibitor_cleanup_task(
                         })
                         .collect();
 
-                    match inhibitors_by_cookie.try_lock() {
-                        Ok(mut inhibitors) => {
-                            inhibitors.retain(|cookie, inhibitor| {
-                                if names.contains(&inhibitor.sender) {
-                                    trace!(cookie, sender=%inhibitor.sender, "Sender still connected, keeping inhibitor alive");
-                                    true
-                                } else {
-                                    info!(cookie, sender=%inhibitor.sender, "Sender not connected, uninhibiting");
-                                    #[cfg(feature = "wayland")]
-                                    if let Err(e) = inhibit_manager.destroy_inhibitor(inhibitor.inhibitor.clone()) {
-                                        error!(cookie, error=?e, "Failed to destroy Wayland inhibitor");
-                                    }
-                                    false
-                                }
-                            });
-                        },
-                        Err(std::sync::TryLockError::WouldBlock) => {
+                    match inhibitors.try_retain_senders(&names).await {
+                        Ok(true) => (),
+                        Ok(false) => {
                             trace!("Inhibitors map already locked, trying again later...");
                             continue
                         },
                         Err(e) => {
                             error!(error=?e, "Terminating heartbeat checker");
-                            anyhow::bail!(format!("Inhibitors map lock error: {:?}", e))
+                            return Err(e)
                         },
                     };
                 }
@@ -337,26 +673,18 @@ async fn inhibitor_cleanup_task(
                 // Absolutely no idea when args() can fail, so just log the error.
                 if let Ok(changed) = sig.args().map_err(|e| error!(error=?e, "Reading NameOwnerChanged failed")) {
                     trace!(changed=?changed, "Received a NameOwnerChanged signal");
+                    if mpris::is_player(changed.name()) {
+                        // Only fails if the MPRIS task has stopped.
+                        let _ = name_owner_changed.send(sig.clone());
+                    }
+                    if heartbeat_interval.is_some() {
+                        continue
+                    }
                     if let zbus::names::BusName::Unique(name) = changed.name() {
                         if changed.new_owner.is_none() && changed.old_owner.as_ref().is_some_and(|x| x == name) {
-                            match inhibitors_by_cookie.lock() {
-                                Ok(mut inhibitors) => {
-                                    #[allow(clippy::needless_bool)]
-                                    inhibitors.retain(|cookie, inhibitor| if &inhibitor.sender == name {
-                                        info!(cookie, sender=%name, "Sender disconnected, uninhibiting");
-                                        #[cfg(feature = "wayland")]
-                                        if let Err(e) = inhibit_manager.destroy_inhibitor(inhibitor.inhibitor.clone()) {
-                                            error!(cookie, error=?e, "Failed to destroy Wayland inhibitor");
-                                        }
-                                        false
-                                    } else {
-                                        true
-                                    });
-                                },
-                                Err(e) => {
-                                    error!(error=?e, "Terminating inhibitor clean up task");
-                                    anyhow::bail!(format!("Inhibitors map lock error: {:?}", e));
-                                },
+                            if let Err(e) = inhibitors.remove_sender(name).await {
+                                error!(error=?e, "Terminating inhibitor clean up task");
+                                return Err(e);
                             }
                         }
                     }
@@ -368,3 +696,98 @@ async fn inhibitor_cleanup_task(
     info!("Stopping inhibitor clean up task");
     Ok(())
 }
+
+/// Release inhibitors once they reach their maximum duration, and tell their holders about it.
+async fn inhibitor_expiry_task(
+    mut terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting inhibitor expiry task");
+    let mut events = inhibitors.events();
+
+    loop {
+        let next_expiry = inhibitors.next_expiry();
+        tokio::select! {
+            x = terminator.changed() => {
+                x?;
+                // Terminator should only ever change from false to true.
+                assert!(*terminator.borrow());
+                break
+            },
+            // Only the added inhibitor can expire sooner than the previous next one, but any event (or missing some)
+            // is a good enough reason to look again.
+            _ = events.recv() => continue,
+            _ = time::sleep_until(next_expiry.unwrap_or_else(time::Instant::now)), if next_expiry.is_some() => (),
+        }
+
+        let expired = match inhibitors.remove_expired().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(error=?e, "Terminating inhibitor expiry task");
+                return Err(e);
+            },
+        };
+        for (cookie, sender) in expired {
+            let emitter = SignalEmitter::new(&connection, control::PATH)?
+                .set_destination(sender.into());
+            if let Err(e) = IoGithubWscreensaverBridgeControlServer::inhibitor_expired(&emitter, cookie).await {
+                error!(cookie, error=?e, "Failed to send InhibitorExpired signal");
+            }
+        }
+    }
+
+    info!("Stopping inhibitor expiry task");
+    Ok(())
+}
+
+/// Emit ActiveChanged at every path org.freedesktop.ScreenSaver is served at as the session goes idle and resumes,
+/// or the locker starts and exits.
+async fn active_changed_task(
+    terminator: watch::Receiver<bool>,
+    idle: watch::Receiver<Option<Instant>>,
+    locked: watch::Receiver<Option<Instant>>,
+    paths: Vec<String>,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting ActiveChanged task");
+
+    enum Message {
+        Terminator(bool),
+        Idle(bool),
+        Locked(bool),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(WatchStream::from_changes(idle).map(|x| Message::Idle(x.is_some()))));
+    stream.push(Box::pin(WatchStream::from_changes(locked).map(|x| Message::Locked(x.is_some()))));
+
+    let (mut idle, mut locked, mut active) = (false, false, false);
+    while let Some(msg) = stream.next().await {
+        match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Idle(x) => idle = x,
+            Message::Locked(x) => locked = x,
+        }
+        // Only emit when going from neither to either and back.
+        if active == (idle || locked) {
+            continue
+        }
+        active = idle || locked;
+        info!(active, idle, locked, "Screensaver active changed");
+        for path in &paths {
+            let emitter = SignalEmitter::new(&connection, path.as_str())?;
+            if let Err(e) = OrgFreedesktopScreenSaverServer::active_changed(&emitter, active).await {
+                error!(path, error=?e, "Failed to emit ActiveChanged");
+            }
+        }
+    }
+
+    info!("Stopping ActiveChanged task");
+    Ok(())
+}
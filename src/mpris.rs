@@ -0,0 +1,167 @@
+// Inhibit while an MPRIS media player is playing, for players that never inhibit themselves.
+use std::collections::HashMap;
+
+use futures_util::stream::{BoxStream, StreamExt};
+use regex::Regex;
+use tokio::sync::{broadcast, watch};
+use tokio_stream::StreamMap;
+use tracing::{error, info, trace, warn};
+use zbus::fdo;
+use zbus::names::BusName;
+use zbus_macros::proxy;
+
+use crate::config;
+use crate::inhibitor::{Inhibitors, Synthetic};
+
+const PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+#[proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_path = "/org/mpris/MediaPlayer2",
+    async_name = "Player",
+)]
+trait OrgMprisMediaPlayer2Player {
+    /// Playing, Paused or Stopped.
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+}
+
+/// Players being watched, by their name without the org.mpris.MediaPlayer2. prefix.
+struct Players {
+    app_ids: Vec<Regex>,
+    connection: zbus::Connection,
+    playing: HashMap<String, bool>,
+    changes: StreamMap<String, BoxStream<'static, Option<String>>>,
+}
+
+impl Players {
+    /// Start watching `name` if it's a player we care about.
+    async fn add(&mut self, name: &str) {
+        let Some(player) = name.strip_prefix(PREFIX) else {
+            return
+        };
+        if !config::any_matches(&self.app_ids, player) || self.changes.contains_key(player) {
+            return
+        }
+
+        let result = async {
+            let proxy = Player::new(&self.connection, name.to_string()).await?;
+            let status = proxy.playback_status().await?;
+            let changes = proxy.receive_playback_status_changed().await
+                .then(|x| async move { x.get().await.ok() });
+            zbus::Result::Ok((status, changes))
+        }.await;
+        match result {
+            Ok((status, changes)) => {
+                trace!(player, status, "Watching media player");
+                self.playing.insert(player.to_string(), status == "Playing");
+                self.changes.insert(player.to_string(), Box::pin(changes));
+            },
+            // Not every MPRIS name implements the Player interface.
+            Err(e) => warn!(error=?e, player, "Unable to watch media player"),
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(player) = name.strip_prefix(PREFIX) {
+            trace!(player, "Media player went away");
+            self.playing.remove(player);
+            self.changes.remove(player);
+        }
+    }
+
+    /// Start over from the names currently on the bus, after missing some NameOwnerChanged signals.
+    async fn reload(&mut self) -> fdo::Result<()> {
+        self.playing.clear();
+        self.changes.clear();
+        let names = fdo::DBusProxy::new(&self.connection).await?.list_names().await?;
+        for name in names {
+            if let BusName::WellKnown(x) = name.inner() {
+                self.add(x.as_str()).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn playing(&self) -> Option<&str> {
+        self.playing.iter().find(|(_, x)| **x).map(|(x, _)| x.as_str())
+    }
+}
+
+/// Hold an inhibitor of our own while any media player matching `app_ids` is Playing. Players appearing and
+/// disappearing are noticed through the NameOwnerChanged signals forwarded by the inhibitor clean up task.
+pub(crate) async fn mpris_task(
+    mut terminator: watch::Receiver<bool>,
+    mut name_owner_changed: broadcast::Receiver<fdo::NameOwnerChanged>,
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+    app_ids: Vec<Regex>,
+) -> anyhow::Result<()> {
+    info!("Starting MPRIS inhibit task");
+    let mut synthetic = Synthetic::new(inhibitors, connection.clone(), "Media player");
+    let mut players = Players {
+        app_ids,
+        connection,
+        playing: HashMap::new(),
+        changes: StreamMap::new(),
+    };
+    players.reload().await?;
+
+    loop {
+        let playing = players.playing().map(|x| x.to_string());
+        if playing.is_some() != synthetic.is_held() {
+            match &playing {
+                Some(x) => info!(player=x, "Media player is playing, inhibiting"),
+                None => info!("No media players playing, uninhibiting"),
+            }
+            let reason = format!("{} is playing", playing.as_deref().unwrap_or_default());
+            if let Err(e) = synthetic.set(playing.is_some(), &reason).await {
+                error!(error=?e, "Failed to update media player inhibitor");
+            }
+        }
+
+        tokio::select! {
+            x = terminator.changed() => {
+                x?;
+                // Terminator should only ever change from false to true.
+                assert!(*terminator.borrow());
+                break
+            },
+            sig = name_owner_changed.recv() => match sig {
+                Ok(sig) => {
+                    let Ok(changed) = sig.args() else {
+                        continue
+                    };
+                    if let BusName::WellKnown(name) = changed.name() {
+                        if changed.new_owner.is_some() {
+                            // A new owner needs a new proxy as well.
+                            players.remove(name.as_str());
+                            players.add(name.as_str()).await;
+                        } else {
+                            players.remove(name.as_str());
+                        }
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!(n, "Missed NameOwnerChanged signals, reloading media players");
+                    players.reload().await?;
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some((player, status)) = players.changes.next() => {
+                trace!(player, ?status, "Media player PlaybackStatus changed");
+                if let Some(status) = status {
+                    players.playing.insert(player, status == "Playing");
+                }
+            },
+        }
+    }
+
+    info!("Stopping MPRIS inhibit task");
+    Ok(())
+}
+
+/// Whether the bus name belongs to a media player, for deciding which NameOwnerChanged signals to forward.
+pub(crate) fn is_player(name: &BusName<'_>) -> bool {
+    name.as_str().starts_with(PREFIX)
+}
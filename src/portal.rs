@@ -0,0 +1,186 @@
+// xdg-desktop-portal backend for org.freedesktop.impl.portal.Inhibit, the portal Flatpak applications inhibit
+// through. Inhibitors are held until the portal closes the Request object it handed us.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use tracing::{error, info, instrument, trace, warn};
+use zbus::message::Header;
+use zbus::fdo;
+use zbus::object_server::ObjectServer;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+use zbus_macros::interface;
+
+use crate::inhibitor::{InhibitFlags, InhibitorEvent, Inhibitors, Origin};
+
+pub(crate) const NAME: &str = "org.freedesktop.impl.portal.desktop.wscreensaver_bridge";
+pub(crate) const PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// Request objects by the cookie of the inhibitor they're holding.
+pub(crate) type Requests = Arc<Mutex<HashMap<u32, OwnedObjectPath>>>;
+
+/// The Request object for a single Inhibit call, closing it releases the inhibitor.
+#[derive(Debug)]
+struct OrgFreedesktopImplPortalRequest {
+    inhibitors: Inhibitors,
+    requests: Requests,
+    cookie: u32,
+}
+
+#[interface(name = "org.freedesktop.impl.portal.Request")]
+impl OrgFreedesktopImplPortalRequest {
+    #[instrument(skip(self, server, hdr), fields(cookie=self.cookie, path=?hdr.path()))]
+    async fn close(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(object_server)]
+        server: &ObjectServer,
+    ) -> fdo::Result<()> {
+        let Some(path) = hdr.path().map(|x| x.to_owned()) else {
+            return Err(fdo::Error::Failed("No path provided".to_string()));
+        };
+
+        self.requests.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on portal requests: {:?}", e)))?
+            .remove(&self.cookie);
+        server.remove::<Self, _>(&path).await?;
+
+        // Might be gone already if the portal went away in between.
+        if self.inhibitors.contains(self.cookie) {
+            self.inhibitors.uninhibit(self.cookie).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OrgFreedesktopImplPortalInhibitServer {
+    pub inhibitors: Inhibitors,
+    pub requests: Requests,
+}
+
+impl OrgFreedesktopImplPortalInhibitServer {
+    /// Returns whether it was exported.
+    fn unexport(&self, cookie: u32) -> fdo::Result<bool> {
+        Ok(self.requests.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on portal requests: {:?}", e)))?
+            .remove(&cookie)
+            .is_some())
+    }
+}
+
+#[interface(name = "org.freedesktop.impl.portal.Inhibit")]
+impl OrgFreedesktopImplPortalInhibitServer {
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, hdr, connection, server, options), fields(sender=?hdr.sender()))]
+    async fn inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
+        #[zbus(object_server)]
+        server: &ObjectServer,
+        handle: OwnedObjectPath,
+        app_id: String,
+        window: String,
+        flags: u32,
+        options: HashMap<String, OwnedValue>,
+    ) -> fdo::Result<()> {
+        let Some(sender) = hdr.sender().map(|x| x.to_owned()) else {
+            let msg = "No sender provided";
+            error!(msg);
+            return Err(fdo::Error::Failed(msg.to_string()));
+        };
+
+        let reason = options.get("reason")
+            .and_then(|x| <&str>::try_from(&**x).ok())
+            .unwrap_or_default();
+        let flags = InhibitFlags::from_bits_truncate(flags);
+        let cookie = self.inhibitors.inhibit(connection, sender, &app_id, reason, flags, Origin::Portal(app_id.clone())).await?;
+
+        let request = OrgFreedesktopImplPortalRequest {
+            inhibitors: self.inhibitors.clone(),
+            requests: self.requests.clone(),
+            cookie,
+        };
+        if let Err(e) = server.at(&handle, request).await {
+            error!(error=?e, "Failed to export Request object");
+            self.inhibitors.uninhibit(cookie).await?;
+            return Err(e.into());
+        }
+        self.requests.lock()
+            .map_err(|e| fdo::Error::Failed(format!("Could not obtain lock on portal requests: {:?}", e)))?
+            .insert(cookie, handle.clone());
+        // Released while exporting, before request_object_task could tell it was exported. Whoever takes it out of
+        // requests removes the object.
+        if !self.inhibitors.contains(cookie) && self.unexport(cookie)? {
+            trace!(cookie, %handle, "Inhibitor released while exporting, removing Request object");
+            if let Err(e) = server.remove::<OrgFreedesktopImplPortalRequest, _>(&handle).await {
+                error!(%handle, error=?e, "Failed to remove Request object");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove Request objects of inhibitors released some other way than through Close, e.g. xdg-desktop-portal
+/// disconnecting.
+pub(crate) async fn request_object_task(
+    terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    requests: Requests,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting portal request object task");
+
+    enum Message {
+        Terminator(bool),
+        Event(Result<InhibitorEvent, BroadcastStreamRecvError>),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(BroadcastStream::new(inhibitors.events()).map(Message::Event)));
+
+    while let Some(msg) = stream.next().await {
+        let removed: Vec<OwnedObjectPath> = match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Event(Ok(InhibitorEvent::Added(_))) => continue,
+            Message::Event(Ok(InhibitorEvent::Removed(cookie))) => {
+                requests.lock()
+                    .map_err(|e| anyhow::anyhow!("Portal requests lock error: {:?}", e))?
+                    .remove(&cookie)
+                    .into_iter()
+                    .collect()
+            },
+            Message::Event(Err(BroadcastStreamRecvError::Lagged(n))) => {
+                warn!(n, "Missed inhibitor events, checking all portal requests");
+                let mut requests = requests.lock()
+                    .map_err(|e| anyhow::anyhow!("Portal requests lock error: {:?}", e))?;
+                let stale: Vec<u32> = requests.keys().copied().filter(|x| !inhibitors.contains(*x)).collect();
+                stale.into_iter().filter_map(|x| requests.remove(&x)).collect()
+            },
+        };
+
+        for path in removed {
+            trace!(%path, "Removing Request object");
+            if let Err(e) = connection.object_server().remove::<OrgFreedesktopImplPortalRequest, _>(&path).await {
+                error!(%path, error=?e, "Failed to remove Request object");
+            }
+        }
+    }
+
+    info!("Stopping portal request object task");
+    Ok(())
+}
+
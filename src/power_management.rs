@@ -0,0 +1,98 @@
+// org.freedesktop.PowerManagement.Inhibit, the predecessor of org.freedesktop.ScreenSaver still used by e.g. VLC.
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use tracing::{error, info, instrument, trace};
+use zbus::message::Header;
+use zbus::fdo;
+use zbus::object_server::SignalEmitter;
+use zbus_macros::interface;
+
+use crate::inhibitor::{InhibitFlags, Inhibitors, Origin};
+
+pub(crate) const NAME: &str = "org.freedesktop.PowerManagement";
+pub(crate) const PATH: &str = "/org/freedesktop/PowerManagement/Inhibit";
+
+#[derive(Debug)]
+pub(crate) struct OrgFreedesktopPowerManagementInhibitServer {
+    pub inhibitors: Inhibitors,
+}
+
+#[interface(name = "org.freedesktop.PowerManagement.Inhibit")]
+impl OrgFreedesktopPowerManagementInhibitServer {
+    #[instrument(skip(self, hdr, connection), fields(sender=?hdr.sender()))]
+    async fn inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        #[zbus(connection)]
+        connection: &zbus::Connection,
+        application: String,
+        reason: String,
+    ) -> fdo::Result<u32> {
+        let Some(sender) = hdr.sender().map(|x| x.to_owned()) else {
+            let msg = "No sender provided";
+            error!(msg);
+            return Err(fdo::Error::Failed(msg.to_string()));
+        };
+
+        // Going by the spec this is about preventing the session from power saving, so we also block suspend.
+        self.inhibitors.inhibit(connection, sender, &application, &reason, InhibitFlags::IDLE | InhibitFlags::SUSPEND, Origin::Client).await
+    }
+
+    #[instrument(skip(self, hdr), fields(uninhibit_sender=?hdr.sender()))]
+    async fn un_inhibit(
+        &self,
+        #[zbus(header)]
+        hdr: Header<'_>,
+        cookie: u32
+    ) -> fdo::Result<()> {
+        self.inhibitors.uninhibit(cookie).await
+    }
+
+    #[instrument(skip(self))]
+    async fn has_inhibit(&self) -> bool {
+        self.inhibitors.is_inhibited()
+    }
+
+    #[zbus(signal)]
+    async fn has_inhibit_changed(emitter: &SignalEmitter<'_>, has_inhibit: bool) -> zbus::Result<()>;
+}
+
+/// Emit HasInhibitChanged whenever we go from having no inhibitors to having some, or vice versa.
+pub(crate) async fn has_inhibit_changed_task(
+    terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+) -> anyhow::Result<()> {
+    info!("Starting HasInhibitChanged task");
+    let emitter = SignalEmitter::new(&connection, PATH)?;
+
+    enum Message {
+        Terminator(bool),
+        Inhibited(bool),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(WatchStream::from_changes(inhibitors.subscribe()).map(Message::Inhibited)));
+
+    while let Some(msg) = stream.next().await {
+        match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Inhibited(x) => {
+                trace!(has_inhibit=x, "Emitting HasInhibitChanged");
+                if let Err(e) = OrgFreedesktopPowerManagementInhibitServer::has_inhibit_changed(&emitter, x).await {
+                    error!(error=?e, "Failed to emit HasInhibitChanged");
+                }
+            },
+        }
+    }
+
+    info!("Stopping HasInhibitChanged task");
+    Ok(())
+}
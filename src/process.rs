@@ -0,0 +1,147 @@
+// Details about the process behind a D-Bus sender, read from /proc.
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use tracing::trace;
+use zbus::fdo;
+use zbus::names::{BusName, UniqueName};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Process {
+    pub pid: u32,
+    pub uid: Option<u32>,
+    /// Target of /proc/<pid>/exe, not readable for processes of other users.
+    pub executable: Option<PathBuf>,
+    /// Name of the process from /proc/<pid>/comm, truncated to 15 characters by the kernel.
+    pub comm: Option<String>,
+    /// The systemd service or scope the process belongs to.
+    pub unit: Option<String>,
+}
+
+impl Process {
+    pub async fn of_sender(connection: &zbus::Connection, sender: &UniqueName<'_>) -> fdo::Result<Self> {
+        let proxy = fdo::DBusProxy::new(connection).await?;
+        let name = BusName::from(sender.as_ref());
+        let (pid, uid) = match proxy.get_connection_credentials(name.clone()).await {
+            Ok(x) => (x.process_id(), x.unix_user_id()),
+            Err(e) => {
+                trace!(error=?e, %sender, "GetConnectionCredentials failed");
+                (None, None)
+            },
+        };
+        // Older buses don't implement GetConnectionCredentials, or may leave out the process ID.
+        let pid = match pid {
+            Some(x) => x,
+            None => proxy.get_connection_unix_process_id(name.clone()).await?,
+        };
+        let uid = match uid {
+            Some(x) => Some(x),
+            None => proxy.get_connection_unix_user(name).await.ok(),
+        };
+        Ok(Self {
+            uid,
+            ..Self::from_pid(pid)
+        })
+    }
+
+    pub fn from_pid(pid: u32) -> Self {
+        Self {
+            pid,
+            uid: None,
+            executable: fs::read_link(format!("/proc/{}/exe", pid)).ok(),
+            comm: fs::read_to_string(format!("/proc/{}/comm", pid)).ok()
+                .map(|x| x.trim_end().to_string()),
+            unit: fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()
+                .and_then(|x| unit_of_cgroup(&x)),
+        }
+    }
+
+    /// The app ID of the Flatpak app the process belongs to, going by its app-flatpak-<app_id>-<n>.scope unit.
+    pub fn flatpak_app_id(&self) -> Option<&str> {
+        self.unit.as_deref().and_then(app_id_of_unit)
+    }
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {}", self.pid)?;
+        if let Some(x) = &self.executable {
+            write!(f, " {}", x.display())?;
+        } else if let Some(x) = &self.comm {
+            write!(f, " {}", x)?;
+        }
+        if let Some(x) = &self.unit {
+            write!(f, " in {}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// The innermost unit in the unified (cgroup v2) hierarchy, e.g. app-firefox-1234.scope for
+/// `0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox-1234.scope`.
+fn unit_of_cgroup(cgroup: &str) -> Option<String> {
+    let path = cgroup.lines().find_map(|x| x.strip_prefix("0::"))?;
+    path.rsplit('/')
+        .find(|x| x.ends_with(".service") || x.ends_with(".scope"))
+        .map(|x| x.to_string())
+}
+
+fn app_id_of_unit(unit: &str) -> Option<&str> {
+    let (app_id, _) = unit.strip_prefix("app-flatpak-")?.strip_suffix(".scope")?.rsplit_once('-')?;
+    Some(app_id).filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified() {
+        assert_eq!(
+            unit_of_cgroup("0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox-1234.scope\n").as_deref(),
+            Some("app-firefox-1234.scope"),
+        );
+        assert_eq!(
+            unit_of_cgroup("0::/user.slice/user-1000.slice/user@1000.service/app.slice/mpd.service\n").as_deref(),
+            Some("mpd.service"),
+        );
+    }
+
+    #[test]
+    fn hybrid() {
+        let cgroup = "\
+12:cpuset:/
+11:memory:/user.slice/user-1000.slice/user@1000.service
+1:name=systemd:/user.slice/user-1000.slice/user@1000.service/app.slice/app-mpv-42.scope
+0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-mpv-42.scope
+";
+        assert_eq!(unit_of_cgroup(cgroup).as_deref(), Some("app-mpv-42.scope"));
+    }
+
+    #[test]
+    fn scope_under_service() {
+        assert_eq!(
+            unit_of_cgroup("0::/user.slice/user-1000.slice/user@1000.service/session.slice/sway.service/app.scope\n").as_deref(),
+            Some("app.scope"),
+        );
+    }
+
+    #[test]
+    fn no_unit() {
+        assert_eq!(unit_of_cgroup("0::/\n"), None);
+        assert_eq!(unit_of_cgroup("0::/user.slice/user-1000.slice\n"), None);
+        // Legacy hierarchy only.
+        assert_eq!(unit_of_cgroup("1:name=systemd:/user.slice/user-1000.slice/session-2.scope\n"), None);
+        assert_eq!(unit_of_cgroup(""), None);
+    }
+
+    #[test]
+    fn flatpak_app_id() {
+        assert_eq!(app_id_of_unit("app-flatpak-org.mozilla.firefox-12345.scope"), Some("org.mozilla.firefox"));
+        assert_eq!(app_id_of_unit("app-flatpak-com.example.foo-bar-7.scope"), Some("com.example.foo-bar"));
+        assert_eq!(app_id_of_unit("app-firefox-1234.scope"), None);
+        assert_eq!(app_id_of_unit("app-flatpak-org.mozilla.firefox-12345.service"), None);
+        assert_eq!(app_id_of_unit("app-flatpak--1.scope"), None);
+    }
+}
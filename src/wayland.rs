@@ -1,27 +1,378 @@
 // get a wayland client
 
-use tracing::trace;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration};
+use tracing::{error, info, trace, warn};
 use wayland_client::{
     backend::WaylandError,
     protocol::{
-        __interfaces::WL_COMPOSITOR_INTERFACE,
+        __interfaces::{WL_COMPOSITOR_INTERFACE, WL_OUTPUT_INTERFACE, WL_SHM_INTERFACE},
+        wl_buffer::WlBuffer,
+        wl_callback::{self, WlCallback},
         wl_compositor::WlCompositor,
+        wl_output::WlOutput,
+        wl_region::WlRegion,
         wl_registry::{self, WlRegistry},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
         wl_surface::WlSurface,
     },
-    Dispatch,
+    delegate_noop, ConnectError, Dispatch, EventQueue, Proxy, QueueHandle,
 };
 
+use wayland_protocols::xdg::shell::client::{
+    __interfaces::XDG_WM_BASE_INTERFACE,
+    xdg_surface::{self, XdgSurface},
+    xdg_toplevel::XdgToplevel,
+    xdg_wm_base::{self, XdgWmBase},
+};
 use wayland_protocols::wp::idle_inhibit::zv1::client::{
     __interfaces::ZWP_IDLE_INHIBIT_MANAGER_V1_INTERFACE,
     zwp_idle_inhibit_manager_v1::ZwpIdleInhibitManagerV1,
     zwp_idle_inhibitor_v1::ZwpIdleInhibitorV1,
 };
 
+use crate::backend::{Backend, Health, Request};
+use crate::inhibitor::InhibitFlags;
+use self::protocols::layer_shell::{
+    __interfaces::ZWLR_LAYER_SHELL_V1_INTERFACE,
+    zwlr_layer_shell_v1::{self, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{self, ZwlrLayerSurfaceV1},
+};
+
+mod fullscreen;
+mod idle;
+mod listen;
+mod protocols;
+
+pub(crate) use self::fullscreen::fullscreen_task;
+pub(crate) use self::idle::spawn_idle_watcher;
+
+pub(crate) const NAME: &str = "wayland";
+
+/// How long to wait for the compositor to answer the registry roundtrip.
+const ROUNDTRIP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often to look for the compositor's socket while waiting for it to appear.
+const WAIT_FOR_DISPLAY_INTERVAL: Duration = Duration::from_millis(250);
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// What inhibitors are attached to. Inhibitors only have to take effect while their surface is visible, which most
+/// compositors don't enforce, but some do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SurfaceMode {
+    /// A surface that is never mapped.
+    #[default]
+    Hidden,
+    /// A transparent 1x1 overlay on every output through wlr-layer-shell.
+    LayerShell,
+    /// A transparent 1x1 window.
+    Toplevel,
+}
+
+impl FromStr for SurfaceMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hidden" => Ok(SurfaceMode::Hidden),
+            "layer-shell" => Ok(SurfaceMode::LayerShell),
+            "toplevel" => Ok(SurfaceMode::Toplevel),
+            _ => Err(format!("unknown inhibit surface {:?}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct DispatcherListener {
+    compositor: Option<WlCompositor>,
     manager: Option<ZwpIdleInhibitManagerV1>,
-    dummy_surface: Option<WlSurface>,
+    shm: Option<WlShm>,
+    layer_shell: Option<ZwlrLayerShellV1>,
+    wm_base: Option<XdgWmBase>,
+    /// wl_output globals by name, and whether any came or went since last looked at.
+    outputs: HashMap<u32, WlOutput>,
+    outputs_changed: bool,
+    /// Set once the compositor has answered our wl_display.sync.
+    synced: bool,
+}
+
+impl DispatcherListener {
+    /// The globals needed for `mode`, or an error listing the ones the compositor lacks.
+    fn globals(&self, mode: SurfaceMode) -> anyhow::Result<Globals> {
+        let missing = || {
+            let missing: Vec<&str> = [
+                (self.compositor.is_none(), WL_COMPOSITOR_INTERFACE.name),
+                (self.manager.is_none(), ZWP_IDLE_INHIBIT_MANAGER_V1_INTERFACE.name),
+                (mode != SurfaceMode::Hidden && self.shm.is_none(), WL_SHM_INTERFACE.name),
+                (mode == SurfaceMode::LayerShell && self.layer_shell.is_none(), ZWLR_LAYER_SHELL_V1_INTERFACE.name),
+                (mode == SurfaceMode::Toplevel && self.wm_base.is_none(), XDG_WM_BASE_INTERFACE.name),
+            ].into_iter().filter_map(|(missing, name)| missing.then_some(name)).collect();
+            anyhow::anyhow!("Wayland compositor lacks the required globals: {}", missing.join(", "))
+        };
+        Ok(Globals {
+            compositor: self.compositor.clone().ok_or_else(missing)?,
+            manager: self.manager.clone().ok_or_else(missing)?,
+            shell: match mode {
+                SurfaceMode::Hidden => Shell::None,
+                SurfaceMode::LayerShell => self.shm.clone().zip(self.layer_shell.clone())
+                    .map(|(shm, layer_shell)| Shell::Layer(shm, layer_shell))
+                    .ok_or_else(missing)?,
+                SurfaceMode::Toplevel => self.shm.clone().zip(self.wm_base.clone())
+                    .map(|(shm, wm_base)| Shell::Xdg(shm, wm_base))
+                    .ok_or_else(missing)?,
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Globals {
+    compositor: WlCompositor,
+    manager: ZwpIdleInhibitManagerV1,
+    shell: Shell,
+}
+
+/// How to map the surface inhibitors are attached to, if at all.
+#[derive(Debug)]
+enum Shell {
+    None,
+    Layer(WlShm, ZwlrLayerShellV1),
+    Xdg(WlShm, XdgWmBase),
+}
+
+/// What a surface needs once the compositor has configured it, to map it.
+#[derive(Debug, Clone)]
+struct Mapping {
+    surface: WlSurface,
+    buffer: WlBuffer,
+}
+
+impl Mapping {
+    fn new(
+        compositor: &WlCompositor,
+        shm: &WlShm,
+        surface: &WlSurface,
+        qhandle: &QueueHandle<DispatcherListener>,
+    ) -> anyhow::Result<Self> {
+        // Let input pass through to whatever is underneath.
+        let region = compositor.create_region(qhandle, ());
+        surface.set_input_region(Some(&region));
+        region.destroy();
+
+        let file = shm_file(4).context("Failed to create shared memory for the inhibit surface")?;
+        let pool = shm.create_pool(file.as_fd(), 4, qhandle, ());
+        // All zeroes, i.e. fully transparent.
+        let buffer = pool.create_buffer(0, 1, 1, 4, wl_shm::Format::Argb8888, qhandle, ());
+        pool.destroy();
+        Ok(Self {
+            surface: surface.clone(),
+            buffer,
+        })
+    }
+
+    fn map(&self) {
+        self.surface.attach(Some(&self.buffer), 0, 0);
+        self.surface.commit();
+    }
+}
+
+/// A surface inhibitors are attached to, along with what keeps it mapped.
+#[derive(Debug)]
+struct InhibitSurface {
+    surface: WlSurface,
+    buffer: Option<WlBuffer>,
+    layer_surface: Option<ZwlrLayerSurfaceV1>,
+    xdg: Option<(XdgSurface, XdgToplevel)>,
+    /// The output the surface is on, for layer-shell.
+    output: Option<WlOutput>,
+    /// Held while inhibited.
+    inhibitor: Option<ZwpIdleInhibitorV1>,
+}
+
+impl InhibitSurface {
+    /// Create a surface with a role that gets it mapped, unless there's no shell to use. With layer-shell it's put on
+    /// `output`, or wherever the compositor likes if None.
+    fn new(
+        globals: &Globals,
+        output: Option<WlOutput>,
+        qhandle: &QueueHandle<DispatcherListener>,
+    ) -> anyhow::Result<Self> {
+        let surface = globals.compositor.create_surface(qhandle, ());
+        let mut this = Self {
+            surface: surface.clone(),
+            buffer: None,
+            layer_surface: None,
+            xdg: None,
+            output,
+            inhibitor: None,
+        };
+        match &globals.shell {
+            Shell::None => (),
+            Shell::Layer(shm, layer_shell) => {
+                let mapping = Mapping::new(&globals.compositor, shm, &surface, qhandle)?;
+                this.buffer = Some(mapping.buffer.clone());
+                let layer_surface = layer_shell.get_layer_surface(
+                    &surface,
+                    this.output.as_ref(),
+                    zwlr_layer_shell_v1::Layer::Overlay,
+                    env!("CARGO_PKG_NAME").to_string(),
+                    qhandle,
+                    mapping,
+                );
+                layer_surface.set_size(1, 1);
+                this.layer_surface = Some(layer_surface);
+            },
+            Shell::Xdg(shm, wm_base) => {
+                let mapping = Mapping::new(&globals.compositor, shm, &surface, qhandle)?;
+                this.buffer = Some(mapping.buffer.clone());
+                let xdg_surface = wm_base.get_xdg_surface(&surface, qhandle, mapping);
+                let toplevel = xdg_surface.get_toplevel(qhandle, ());
+                toplevel.set_title(env!("CARGO_PKG_NAME").to_string());
+                toplevel.set_app_id(env!("CARGO_PKG_NAME").to_string());
+                // A fixed size gets it floated rather than tiled by the tiling compositors that go by it, e.g. Sway.
+                toplevel.set_min_size(1, 1);
+                toplevel.set_max_size(1, 1);
+                this.xdg = Some((xdg_surface, toplevel));
+            },
+        }
+        // The initial commit without a buffer gets the role configured, it's mapped once that arrives.
+        surface.commit();
+        Ok(this)
+    }
+
+    fn set_inhibited(
+        &mut self,
+        inhibited: bool,
+        manager: &ZwpIdleInhibitManagerV1,
+        qhandle: &QueueHandle<DispatcherListener>,
+    ) {
+        match (inhibited, self.inhibitor.take()) {
+            (true, None) => self.inhibitor = Some(manager.create_inhibitor(&self.surface, qhandle, ())),
+            // The Wayland idle-inhibit protocol requires that we explicitly destroy the inhibitors.
+            (false, Some(inhibitor)) => inhibitor.destroy(),
+            (_, inhibitor) => self.inhibitor = inhibitor,
+        }
+    }
+
+    fn destroy(mut self) {
+        if let Some(inhibitor) = self.inhibitor.take() {
+            inhibitor.destroy();
+        }
+        if let Some(layer_surface) = self.layer_surface.take() {
+            layer_surface.destroy();
+        }
+        if let Some((xdg_surface, toplevel)) = self.xdg.take() {
+            toplevel.destroy();
+            xdg_surface.destroy();
+        }
+        self.surface.destroy();
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+        // Only from version 3 on.
+        if let Some(output) = self.output.take().filter(|x| x.version() >= 3) {
+            output.release();
+        }
+    }
+}
+
+/// An unlinked file of `size` zeroes, to share with the compositor.
+fn shm_file(size: u64) -> io::Result<File> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").map_or_else(std::env::temp_dir, PathBuf::from);
+    let path = dir.join(format!("{}-{}-{}", env!("CARGO_PKG_NAME"), std::process::id(), fastrand::u32(..)));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).mode(0o600).open(&path)?;
+    fs::remove_file(&path)?;
+    file.set_len(size)?;
+    Ok(file)
+}
+
+delegate_noop!(DispatcherListener: ignore WlShm);
+delegate_noop!(DispatcherListener: WlShmPool);
+delegate_noop!(DispatcherListener: ignore WlBuffer);
+delegate_noop!(DispatcherListener: WlRegion);
+delegate_noop!(DispatcherListener: ZwlrLayerShellV1);
+delegate_noop!(DispatcherListener: ignore XdgToplevel);
+delegate_noop!(DispatcherListener: ignore WlOutput);
+
+impl Dispatch<ZwlrLayerSurfaceV1, Mapping> for DispatcherListener {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrLayerSurfaceV1,
+        event: <ZwlrLayerSurfaceV1 as wayland_client::Proxy>::Event,
+        data: &Mapping,
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_layer_surface_v1::Event::Configure { serial, .. } => {
+                trace!("Mapping inhibit surface");
+                proxy.ack_configure(serial);
+                data.map();
+            },
+            // E.g. when its output goes away, which we'll hear about from the registry as well.
+            zwlr_layer_surface_v1::Event::Closed => trace!("Compositor closed the inhibit surface"),
+        }
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for DispatcherListener {
+    fn event(
+        _state: &mut Self,
+        proxy: &XdgWmBase,
+        event: <XdgWmBase as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            proxy.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, Mapping> for DispatcherListener {
+    fn event(
+        _state: &mut Self,
+        proxy: &XdgSurface,
+        event: <XdgSurface as wayland_client::Proxy>::Event,
+        data: &Mapping,
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            trace!("Mapping inhibit surface");
+            proxy.ack_configure(serial);
+            data.map();
+        }
+    }
+}
+
+impl Dispatch<WlCallback, ()> for DispatcherListener {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlCallback,
+        event: <WlCallback as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.synced = true;
+        }
+    }
 }
 
 // TODO: how do I move these into another file?
@@ -82,81 +433,396 @@ impl Dispatch<WlRegistry, ()> for DispatcherListener {
         _conn: &wayland_client::Connection,
         qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        match event {
-            wl_registry::Event::Global {
-                name,
-                interface,
-                version,
-            } => {
-                if interface == WL_COMPOSITOR_INTERFACE.name {
-                    trace!("Found compositor");
-                    let compositor =
-                        registry.bind::<WlCompositor, _, _>(name, version, qhandle, ());
-                    let surface = compositor.create_surface(&qhandle, ());
-                    state.dummy_surface = Some(surface);
-                }
-                if interface == ZWP_IDLE_INHIBIT_MANAGER_V1_INTERFACE.name {
-                    trace!("Found inhibit manager");
-                    let manager =
-                        registry.bind::<ZwpIdleInhibitManagerV1, _, _>(name, version, qhandle, ());
-                    state.manager = Some(manager);
-                    // signal to stop waiting
-                }
+        if let wl_registry::Event::GlobalRemove { name } = event {
+            if state.outputs.remove(&name).is_some() {
+                trace!(name, "Output removed");
+                state.outputs_changed = true;
+            }
+        } else if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event {
+            if interface == WL_COMPOSITOR_INTERFACE.name {
+                trace!("Found compositor");
+                state.compositor = Some(registry.bind::<WlCompositor, _, _>(name, version, qhandle, ()));
+            }
+            if interface == ZWP_IDLE_INHIBIT_MANAGER_V1_INTERFACE.name {
+                trace!("Found inhibit manager");
+                let manager =
+                    registry.bind::<ZwpIdleInhibitManagerV1, _, _>(name, version, qhandle, ());
+                state.manager = Some(manager);
+            }
+            if interface == WL_SHM_INTERFACE.name {
+                trace!("Found shm");
+                state.shm = Some(registry.bind::<WlShm, _, _>(name, 1, qhandle, ()));
+            }
+            if interface == ZWLR_LAYER_SHELL_V1_INTERFACE.name {
+                trace!(version, "Found layer shell");
+                state.layer_shell = Some(registry.bind::<ZwlrLayerShellV1, _, _>(name, version.min(4), qhandle, ()));
+            }
+            if interface == WL_OUTPUT_INTERFACE.name {
+                trace!(name, "Output added");
+                state.outputs.insert(name, registry.bind::<WlOutput, _, _>(name, version.min(3), qhandle, ()));
+                state.outputs_changed = true;
+            }
+            if interface == XDG_WM_BASE_INTERFACE.name {
+                trace!("Found xdg wm base");
+                state.wm_base = Some(registry.bind::<XdgWmBase, _, _>(name, 1, qhandle, ()));
             }
-            _ => {}
         }
     }
 }
 
+/// A connection to the compositor driven by the runtime through its socket, rather than by blocking on it.
 #[derive(Debug)]
-pub(crate) struct InhibitorManager {
-    manager: ZwpIdleInhibitManagerV1,
-    dummy_surface: WlSurface,
-    queue_handle: wayland_client::QueueHandle<DispatcherListener>,
+struct WaylandConnection {
+    /// Borrows the socket owned by `conn`, so declared first to be dropped before it.
+    fd: AsyncFd<RawFd>,
     conn: wayland_client::Connection,
+    event_queue: EventQueue<DispatcherListener>,
+    listener: DispatcherListener,
+    globals: Globals,
+    /// What inhibitors are attached to, by the name of the wl_output global they're on, if any. With layer-shell
+    /// there's one on every output, otherwise just the one.
+    surfaces: HashMap<Option<u32>, InhibitSurface>,
+    inhibited: bool,
 }
 
-impl InhibitorManager {
-    pub fn create_inhibitor(&self) -> Result<ZwpIdleInhibitorV1, WaylandError> {
-        let inhibitor = self
-            .manager
-            .create_inhibitor(&self.dummy_surface, &self.queue_handle, ());
-        match self.conn.roundtrip() {
-            Ok(_) => Ok(inhibitor),
-            Err(e) => Err(e),
+impl WaylandConnection {
+    /// Connect to the compositor given by WAYLAND_DISPLAY, waiting up to `wait_for_display` for its socket to
+    /// appear, and check that it has the globals we need for `mode`.
+    async fn connect(wait_for_display: Duration, mode: SurfaceMode) -> anyhow::Result<Self> {
+        let conn = connect_to_env(wait_for_display).await?;
+        time::timeout(ROUNDTRIP_TIMEOUT, Self::new(conn, mode)).await
+            .context("Timed out waiting for the Wayland compositor to list its globals")?
+    }
+
+    async fn new(conn: wayland_client::Connection, mode: SurfaceMode) -> anyhow::Result<Self> {
+        let mut event_queue = conn.new_event_queue();
+        let fd = AsyncFd::with_interest(
+            conn.backend().poll_fd().as_raw_fd(),
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        // registry is returned in the dispatch
+        let _ = conn.display().get_registry(&event_queue.handle(), ());
+        // The compositor advertises every global before answering the sync.
+        let _ = conn.display().sync(&event_queue.handle(), ());
+
+        let mut listener = DispatcherListener::default();
+        while !listener.synced {
+            dispatch(&conn, &mut event_queue, &fd, &mut listener).await?;
+        }
+        let globals = listener.globals(mode)?;
+        let mut this = Self {
+            fd,
+            conn,
+            event_queue,
+            listener,
+            globals,
+            surfaces: HashMap::new(),
+            inhibited: false,
+        };
+        if matches!(this.globals.shell, Shell::Layer(..)) {
+            this.update_outputs()?;
+        } else {
+            let surface = InhibitSurface::new(&this.globals, None, &this.event_queue.handle())?;
+            this.surfaces.insert(None, surface);
         }
+        flush(&this.conn)?;
+        Ok(this)
     }
 
-    pub fn destroy_inhibitor(&self, inhibitor: ZwpIdleInhibitorV1) -> Result<(), WaylandError> {
-        inhibitor.destroy();
-        match self.conn.roundtrip() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
+    /// Wait for events and dispatch them. Cancel safe, nothing is lost if dropped while waiting.
+    async fn dispatch(&mut self) -> anyhow::Result<()> {
+        dispatch(&self.conn, &mut self.event_queue, &self.fd, &mut self.listener).await?;
+        if matches!(self.globals.shell, Shell::Layer(..)) {
+            self.update_outputs()?;
         }
+        flush(&self.conn)
+    }
+
+    /// Follow outputs coming and going with a surface of their own, inhibiting on the new ones if inhibited.
+    fn update_outputs(&mut self) -> anyhow::Result<()> {
+        if !std::mem::take(&mut self.listener.outputs_changed) {
+            return Ok(())
+        }
+        let removed: Vec<Option<u32>> = self.surfaces.keys()
+            .filter(|x| !x.is_some_and(|y| self.listener.outputs.contains_key(&y)))
+            .copied()
+            .collect();
+        for name in removed {
+            if let Some(surface) = self.surfaces.remove(&name) {
+                trace!(output=name, "Destroying inhibit surface of removed output");
+                surface.destroy();
+            }
+        }
+
+        let qhandle = self.event_queue.handle();
+        for (name, output) in &self.listener.outputs {
+            if self.surfaces.contains_key(&Some(*name)) {
+                continue
+            }
+            trace!(output=name, inhibited=self.inhibited, "Creating inhibit surface for output");
+            let mut surface = InhibitSurface::new(&self.globals, Some(output.clone()), &qhandle)?;
+            surface.set_inhibited(self.inhibited, &self.globals.manager, &qhandle);
+            self.surfaces.insert(Some(*name), surface);
+        }
+        if self.surfaces.is_empty() {
+            warn!("No outputs to put inhibit surfaces on, inhibitors will have no effect until there are");
+        }
+        Ok(())
+    }
+
+    /// Create or destroy the inhibitor on every surface.
+    fn set_inhibited(&mut self, inhibited: bool) -> anyhow::Result<()> {
+        if inhibited == self.inhibited {
+            return Ok(())
+        }
+        self.inhibited = inhibited;
+        let qhandle = self.event_queue.handle();
+        for surface in self.surfaces.values_mut() {
+            surface.set_inhibited(inhibited, &self.globals.manager, &qhandle);
+        }
+        flush(&self.conn)
     }
 }
 
-pub async fn get_inhibit_manager() -> anyhow::Result<InhibitorManager> {
-    // get wayland display
-    let conn =
-        wayland_client::Connection::connect_to_env().expect("Failed to connect to Wayland server");
-    let mut event_queue = conn.new_event_queue();
-    let display = conn.display();
-    let qh = event_queue.handle();
-    // registry is returned in the dispatch
-    let _ = display.get_registry(&qh, ());
+/// The compositor may not have created its socket yet at session start up, so keep looking for it for up to
+/// `wait_for_display`.
+async fn connect_to_env(wait_for_display: Duration) -> anyhow::Result<wayland_client::Connection> {
+    let deadline = time::Instant::now() + wait_for_display;
+    loop {
+        match wayland_client::Connection::connect_to_env() {
+            Ok(x) => return Ok(x),
+            Err(ConnectError::NoCompositor) if time::Instant::now() < deadline => {
+                trace!("No Wayland compositor yet, waiting for it");
+                time::sleep(WAIT_FOR_DISPLAY_INTERVAL).await;
+            },
+            Err(e) => return Err(e).context("Failed to connect to Wayland server"),
+        }
+    }
+}
+
+/// Send the requests made so far. A full socket isn't an error, the rest gets sent by the next dispatch, which waits
+/// for the socket to take more.
+fn flush(conn: &wayland_client::Connection) -> anyhow::Result<()> {
+    try_flush(conn)?;
+    Ok(())
+}
 
-    let mut dl = DispatcherListener::default();
+/// Like [`flush`], but returns `Ok(false)` if the socket was full and some requests are still queued.
+fn try_flush(conn: &wayland_client::Connection) -> anyhow::Result<bool> {
+    match conn.flush() {
+        Ok(()) => Ok(true),
+        Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
 
+async fn dispatch<State>(
+    conn: &wayland_client::Connection,
+    event_queue: &mut EventQueue<State>,
+    fd: &AsyncFd<RawFd>,
+    state: &mut State,
+) -> anyhow::Result<()> {
     loop {
-        event_queue.blocking_dispatch(&mut dl).unwrap();
-        if dl.manager.is_some() && dl.dummy_surface.is_some() {
-            return Ok(InhibitorManager {
-                manager: dl.manager.take().unwrap(),
-                dummy_surface: dl.dummy_surface.take().unwrap(),
-                queue_handle: qh,
-                conn,
-            });
+        event_queue.dispatch_pending(state)?;
+        let flushed = try_flush(conn)?;
+        // None if events were queued in the mean time, dispatch those first.
+        let Some(guard) = event_queue.prepare_read() else {
+            continue
+        };
+        let mut ready = tokio::select! {
+            x = fd.readable() => x?,
+            // Requests left over from a full socket go out once it takes more.
+            x = fd.writable(), if !flushed => {
+                x?.clear_ready();
+                continue
+            },
+        };
+        match guard.read() {
+            Ok(_) => break,
+            Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => ready.clear_ready(),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    event_queue.dispatch_pending(state)?;
+    Ok(())
+}
+
+#[derive(Debug)]
+enum Command {
+    Acquire(u32),
+    Release(u32),
+}
+
+/// Inhibits for as long as any cookie is held, on behalf of [`WaylandBackend`], with a single inhibitor per surface
+/// however many cookies there are. If the connection to the compositor is lost, the cookies are kept and the
+/// inhibitors re-created once reconnected.
+#[derive(Debug)]
+pub(crate) struct Driver {
+    commands: mpsc::UnboundedReceiver<Command>,
+    /// None while disconnected from the compositor.
+    connection: Option<WaylandConnection>,
+    /// Cookies that want the session inhibited.
+    cookies: HashSet<u32>,
+    status: watch::Sender<Option<String>>,
+    /// What to attach the inhibitors to on each new connection.
+    mode: SurfaceMode,
+}
+
+impl Driver {
+    /// Forget the connection and the inhibitors created on it.
+    fn disconnected(&mut self, error: anyhow::Error) {
+        error!(error=?error, "Lost connection to the Wayland compositor");
+        self.connection = None;
+        self.status.send_replace(Some(format!("Reconnecting to the Wayland compositor, lost connection: {:#}", error)));
+    }
+
+    fn reconnected(&mut self, connection: WaylandConnection) {
+        info!("Reconnected to the Wayland compositor");
+        self.connection = Some(connection);
+        self.status.send_replace(None);
+        self.update();
+        if !self.cookies.is_empty() {
+            info!(cookies=self.cookies.len(), "Re-created Wayland inhibitors");
+        }
+    }
+
+    /// Create the inhibitors if there are cookies, or destroy them if there are none left.
+    fn update(&mut self) {
+        let Some(connection) = self.connection.as_mut() else {
+            if !self.cookies.is_empty() {
+                warn!("Not connected to the Wayland compositor, inhibiting once reconnected");
+            }
+            return
+        };
+        let inhibited = !self.cookies.is_empty();
+        if inhibited != connection.inhibited {
+            trace!(cookies=self.cookies.len(), "{} inhibitors", if inhibited { "Creating" } else { "Destroying" });
+        }
+        // Losing the connection takes the inhibitors with it, so no need to fail.
+        if let Err(e) = connection.set_inhibited(inhibited) {
+            self.disconnected(e);
+        }
+    }
+
+    /// Run the connection, reconnecting with an exponential back off whenever it's lost.
+    pub async fn run(mut self, mut terminator: watch::Receiver<bool>) -> anyhow::Result<()> {
+        info!("Starting Wayland connection task");
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+
+        loop {
+            tokio::select! {
+                // Commands first, so the releases sent just before terminating are still carried out.
+                biased;
+                Some(command) = self.commands.recv() => {
+                    trace!(?command, "Received command");
+                    match command {
+                        Command::Acquire(cookie) => self.cookies.insert(cookie),
+                        Command::Release(cookie) => self.cookies.remove(&cookie),
+                    };
+                    self.update();
+                },
+                x = terminator.changed() => {
+                    x?;
+                    // Terminator should only ever change from false to true.
+                    assert!(*terminator.borrow());
+                    break
+                },
+                Some(result) = dispatch_if_connected(&mut self.connection) => {
+                    if let Err(e) = result {
+                        self.disconnected(e);
+                        backoff = RECONNECT_BACKOFF_MIN;
+                    }
+                },
+                _ = time::sleep(backoff), if self.connection.is_none() => {
+                    info!("Reconnecting to the Wayland compositor");
+                    match WaylandConnection::connect(Duration::ZERO, self.mode).await {
+                        Ok(connection) => self.reconnected(connection),
+                        Err(e) => warn!(error=?e, retry_in=?backoff, "Failed to reconnect to the Wayland compositor"),
+                    }
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                },
+            }
+        }
+
+        info!("Stopping Wayland connection task");
+        Ok(())
+    }
+}
+
+/// Dispatch events if connected, otherwise never completes.
+async fn dispatch_if_connected(connection: &mut Option<WaylandConnection>) -> Option<anyhow::Result<()>> {
+    match connection {
+        Some(x) => Some(x.dispatch().await),
+        None => None,
+    }
+}
+
+/// Inhibits through the compositor's idle-inhibit protocol. The connection itself is owned by [`Driver`], so that
+/// talking to the compositor never blocks the D-Bus method calls.
+#[derive(Debug, Clone)]
+pub(crate) struct WaylandBackend {
+    commands: mpsc::UnboundedSender<Command>,
+    /// Why the connection is unhealthy, None while connected.
+    status: watch::Receiver<Option<String>>,
+}
+
+impl WaylandBackend {
+    /// Connect to the compositor, waiting up to `wait_for_display` for it to show up, with inhibitors attached to a
+    /// surface mapped according to `mode`. The returned [`Driver`] must be run for the backend to do anything.
+    pub async fn connect(wait_for_display: Duration, mode: SurfaceMode) -> anyhow::Result<(Self, Driver)> {
+        let connection = WaylandConnection::connect(wait_for_display, mode).await?;
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (status_tx, status_rx) = watch::channel(None);
+        let backend = Self {
+            commands: commands_tx,
+            status: status_rx,
+        };
+        let driver = Driver {
+            commands: commands_rx,
+            connection: Some(connection),
+            cookies: HashSet::new(),
+            status: status_tx,
+            mode,
+        };
+        Ok((backend, driver))
+    }
+
+    fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.commands.send(command).map_err(|_| anyhow::anyhow!("Wayland connection task has stopped"))
+    }
+}
+
+#[async_trait]
+impl Backend for WaylandBackend {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn flags(&self) -> InhibitFlags {
+        InhibitFlags::IDLE
+    }
+
+    /// Succeeds while disconnected, the inhibitor gets created once reconnected.
+    async fn acquire(&self, request: &Request<'_>) -> anyhow::Result<()> {
+        trace!(cookie=request.cookie, flags=?request.flags, "Inhibiting for {} because {}", request.application_name, request.reason);
+        self.send(Command::Acquire(request.cookie))
+    }
+
+    async fn release(&self, cookie: u32) -> anyhow::Result<()> {
+        self.send(Command::Release(cookie))
+    }
+
+    async fn health(&self) -> Health {
+        if self.commands.is_closed() {
+            return Health::Unhealthy("Wayland connection task has stopped".to_string());
+        }
+        match &*self.status.borrow() {
+            None => Health::Healthy,
+            Some(e) => Health::Unhealthy(e.clone()),
         }
     }
 }
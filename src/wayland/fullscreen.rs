@@ -0,0 +1,211 @@
+// Inhibit while a window is fullscreen, for players that never inhibit themselves.
+
+use std::collections::HashMap;
+
+use futures_util::stream::{BoxStream, SelectAll, StreamExt};
+use regex::Regex;
+use tokio::sync::watch;
+use tokio_stream::wrappers::WatchStream;
+use tracing::{error, info, trace, warn};
+use wayland_client::{
+    backend::ObjectId,
+    protocol::{
+        wl_callback::WlCallback,
+        wl_registry::{self, WlRegistry},
+    },
+    delegate_noop, Dispatch, Proxy, QueueHandle,
+};
+
+use crate::config;
+use crate::inhibitor::{Inhibitors, Synthetic};
+use super::listen::{listen, Listener};
+use super::protocols::foreign_toplevel::{
+    __interfaces::ZWLR_FOREIGN_TOPLEVEL_MANAGER_V1_INTERFACE,
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+
+#[derive(Debug, Default)]
+struct Toplevel {
+    app_id: String,
+    fullscreen: bool,
+}
+
+/// Tracks toplevels through wlr-foreign-toplevel-management, on a connection of its own.
+#[derive(Debug)]
+struct ToplevelListener {
+    manager: Option<ZwlrForeignToplevelManagerV1>,
+    toplevels: HashMap<ObjectId, Toplevel>,
+    app_ids: Vec<Regex>,
+    /// The app_id of a matching fullscreen toplevel, if there are any.
+    fullscreen: watch::Sender<Option<String>>,
+}
+
+impl ToplevelListener {
+    fn update(&self) {
+        let app_id = self.toplevels.values()
+            .find(|x| x.fullscreen && config::any_matches(&self.app_ids, &x.app_id))
+            .map(|x| x.app_id.clone());
+        self.fullscreen.send_if_modified(|x| {
+            // Only care about going from none to some and back, not which one it is.
+            let changed = x.is_some() != app_id.is_some();
+            if changed {
+                *x = app_id;
+            }
+            changed
+        });
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for ToplevelListener {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event {
+            if interface == ZWLR_FOREIGN_TOPLEVEL_MANAGER_V1_INTERFACE.name {
+                trace!(version, "Found foreign toplevel manager");
+                if version < 2 {
+                    warn!(version, "Foreign toplevel manager is too old to report fullscreen toplevels");
+                }
+                let manager =
+                    registry.bind::<ZwlrForeignToplevelManagerV1, _, _>(name, version.min(3), qhandle, ());
+                state.manager = Some(manager);
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for ToplevelListener {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrForeignToplevelManagerV1,
+        event: <ZwlrForeignToplevelManagerV1 as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
+                state.toplevels.insert(toplevel.id(), Toplevel::default());
+            },
+            zwlr_foreign_toplevel_manager_v1::Event::Finished => {
+                warn!("Compositor stopped sending foreign toplevels");
+                state.manager = None;
+            },
+        }
+    }
+
+    wayland_client::event_created_child!(ToplevelListener, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for ToplevelListener {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: <ZwlrForeignToplevelHandleV1 as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                state.toplevels.entry(handle.id()).or_default().app_id = app_id;
+            },
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => {
+                let fullscreen = zwlr_foreign_toplevel_handle_v1::State::Fullscreen as u32;
+                state.toplevels.entry(handle.id()).or_default().fullscreen = states.chunks_exact(4)
+                    .any(|x| u32::from_ne_bytes([x[0], x[1], x[2], x[3]]) == fullscreen);
+            },
+            zwlr_foreign_toplevel_handle_v1::Event::Done => state.update(),
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                state.toplevels.remove(&handle.id());
+                handle.destroy();
+                state.update();
+            },
+            _ => (),
+        }
+    }
+}
+
+delegate_noop!(ToplevelListener: ignore WlCallback);
+
+impl Listener for ToplevelListener {
+    const UNSUPPORTED: &str =
+        "Compositor doesn't support wlr-foreign-toplevel-management, unable to inhibit for fullscreen windows";
+    const LOST: &str = "Lost track of fullscreen windows";
+
+    fn bind(&mut self, _qhandle: &QueueHandle<Self>) -> anyhow::Result<bool> {
+        if self.manager.is_none() {
+            return Ok(false)
+        }
+        info!("Watching for fullscreen windows");
+        Ok(true)
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.manager.is_some(), "Foreign toplevel manager finished");
+        Ok(())
+    }
+}
+
+/// Hold an inhibitor of our own while any window matching `app_ids` is fullscreen.
+pub(crate) async fn fullscreen_task(
+    terminator: watch::Receiver<bool>,
+    inhibitors: Inhibitors,
+    connection: zbus::Connection,
+    app_ids: Vec<Regex>,
+) -> anyhow::Result<()> {
+    info!("Starting fullscreen inhibit task");
+    let (fullscreen_tx, fullscreen_rx) = watch::channel(None);
+    // Ends once fullscreen_rx is dropped on the way out.
+    tokio::spawn(listen(fullscreen_tx, move |tx| ToplevelListener {
+        manager: None,
+        toplevels: HashMap::new(),
+        app_ids: app_ids.clone(),
+        fullscreen: tx,
+    }));
+    let mut synthetic = Synthetic::new(inhibitors, connection, "Fullscreen");
+
+    enum Message {
+        Terminator(bool),
+        Fullscreen(Option<String>),
+    }
+
+    let mut stream: SelectAll<BoxStream<Message>> = SelectAll::new();
+    stream.push(Box::pin(WatchStream::from_changes(terminator).map(Message::Terminator)));
+    stream.push(Box::pin(WatchStream::from_changes(fullscreen_rx).map(Message::Fullscreen)));
+
+    while let Some(msg) = stream.next().await {
+        match msg {
+            Message::Terminator(x) => {
+                // Terminator should only ever change from false to true.
+                assert!(x);
+                break
+            },
+            Message::Fullscreen(app_id) => {
+                match &app_id {
+                    Some(x) => info!(app_id=x, "Window went fullscreen, inhibiting"),
+                    None => info!("No fullscreen windows left, uninhibiting"),
+                }
+                let reason = format!("{} is fullscreen", app_id.as_deref().unwrap_or_default());
+                if let Err(e) = synthetic.set(app_id.is_some(), &reason).await {
+                    error!(error=?e, "Failed to update fullscreen inhibitor");
+                }
+            },
+        }
+    }
+
+    info!("Stopping fullscreen inhibit task");
+    Ok(())
+}
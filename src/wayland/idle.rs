@@ -0,0 +1,175 @@
+// Track whether the session is idle, for GetActive, GetSessionIdleTime and ActiveChanged.
+
+use std::time::Instant;
+
+use tokio::sync::watch;
+use tokio::time::Duration;
+use tracing::{info, trace};
+use wayland_client::{
+    protocol::{
+        wl_callback::WlCallback,
+        wl_registry::{self, WlRegistry},
+        wl_seat::WlSeat,
+    },
+    delegate_noop, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    __interfaces::EXT_IDLE_NOTIFIER_V1_INTERFACE,
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+
+use super::listen::{listen, Listener};
+use super::protocols::kde_idle::{
+    __interfaces::ORG_KDE_KWIN_IDLE_INTERFACE,
+    org_kde_kwin_idle::OrgKdeKwinIdle,
+    org_kde_kwin_idle_timeout::{self, OrgKdeKwinIdleTimeout},
+};
+
+/// Tracks whether the session is idle through ext-idle-notify-v1, or org_kde_kwin_idle if the compositor only has
+/// that, on a connection of its own.
+#[derive(Debug)]
+struct IdleListener {
+    timeout: Duration,
+    seat: Option<WlSeat>,
+    notifier: Option<ExtIdleNotifierV1>,
+    kde_idle: Option<OrgKdeKwinIdle>,
+    /// When the session went idle, None while it's not.
+    idle: watch::Sender<Option<Instant>>,
+}
+
+impl Dispatch<WlRegistry, ()> for IdleListener {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event {
+            // Idle is tracked per seat, but there's rarely more than one.
+            if interface == WlSeat::interface().name && state.seat.is_none() {
+                trace!("Found seat");
+                state.seat = Some(registry.bind::<WlSeat, _, _>(name, 1, qhandle, ()));
+            }
+            if interface == EXT_IDLE_NOTIFIER_V1_INTERFACE.name {
+                trace!(version, "Found idle notifier");
+                state.notifier = Some(registry.bind::<ExtIdleNotifierV1, _, _>(name, 1, qhandle, ()));
+            }
+            if interface == ORG_KDE_KWIN_IDLE_INTERFACE.name {
+                trace!(version, "Found KDE idle");
+                state.kde_idle = Some(registry.bind::<OrgKdeKwinIdle, _, _>(name, 1, qhandle, ()));
+            }
+        }
+    }
+}
+
+impl Dispatch<WlSeat, ()> for IdleListener {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlSeat,
+        _event: <WlSeat as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for IdleListener {
+    fn event(
+        _state: &mut Self,
+        _proxy: &ExtIdleNotifierV1,
+        _event: <ExtIdleNotifierV1 as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for IdleListener {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtIdleNotificationV1,
+        event: <ExtIdleNotificationV1 as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.idle.send_replace(Some(Instant::now())),
+            ext_idle_notification_v1::Event::Resumed => state.idle.send_replace(None),
+            _ => None,
+        };
+    }
+}
+
+impl Dispatch<OrgKdeKwinIdle, ()> for IdleListener {
+    fn event(
+        _state: &mut Self,
+        _proxy: &OrgKdeKwinIdle,
+        _event: <OrgKdeKwinIdle as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<OrgKdeKwinIdleTimeout, ()> for IdleListener {
+    fn event(
+        state: &mut Self,
+        _proxy: &OrgKdeKwinIdleTimeout,
+        event: <OrgKdeKwinIdleTimeout as wayland_client::Proxy>::Event,
+        _data: &(),
+        _conn: &wayland_client::Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            org_kde_kwin_idle_timeout::Event::Idle => state.idle.send_replace(Some(Instant::now())),
+            org_kde_kwin_idle_timeout::Event::Resumed => state.idle.send_replace(None),
+        };
+    }
+}
+
+delegate_noop!(IdleListener: ignore WlCallback);
+
+impl Listener for IdleListener {
+    const UNSUPPORTED: &str =
+        "Compositor supports neither ext-idle-notify-v1 nor org_kde_kwin_idle, unable to tell when the session is idle";
+    const LOST: &str = "Lost track of idle";
+
+    fn bind(&mut self, qhandle: &QueueHandle<Self>) -> anyhow::Result<bool> {
+        let Some(seat) = self.seat.as_ref() else {
+            return Ok(false)
+        };
+        let timeout_ms = u32::try_from(self.timeout.as_millis()).unwrap_or(u32::MAX);
+        if let Some(notifier) = self.notifier.as_ref() {
+            info!("Watching for idle through ext-idle-notify-v1");
+            notifier.get_idle_notification(timeout_ms, seat, qhandle, ());
+        } else if let Some(kde_idle) = self.kde_idle.as_ref() {
+            info!("Watching for idle through org_kde_kwin_idle");
+            kde_idle.get_idle_timeout(seat, timeout_ms, qhandle, ());
+        } else {
+            return Ok(false)
+        }
+        Ok(true)
+    }
+}
+
+/// Track whether the session has been idle for `timeout`, sending when it went idle to `idle`.
+pub(crate) fn spawn_idle_watcher(timeout: Duration, idle: watch::Sender<Option<Instant>>) {
+    tokio::spawn(listen(idle, move |tx| IdleListener {
+        timeout,
+        seat: None,
+        notifier: None,
+        kde_idle: None,
+        idle: tx,
+    }));
+}
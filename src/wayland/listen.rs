@@ -0,0 +1,88 @@
+// Connections to the compositor of their own that only listen for events, shared by the watchers.
+
+use std::os::fd::AsRawFd;
+
+use anyhow::Context as _;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tracing::{error, warn};
+use wayland_client::{
+    protocol::{wl_callback::WlCallback, wl_registry::WlRegistry},
+    Dispatch, Proxy, QueueHandle,
+};
+
+use super::{connect_to_env, dispatch, flush, RECONNECT_BACKOFF_MAX, RECONNECT_BACKOFF_MIN, ROUNDTRIP_TIMEOUT};
+
+/// State of a connection of its own that only listens for events, for [`listen`].
+pub(super) trait Listener: Dispatch<WlRegistry, ()> + Dispatch<WlCallback, ()> + Sized + Send + 'static {
+    /// Logged when the compositor lacks what's needed.
+    const UNSUPPORTED: &str;
+    /// Logged when the connection is lost.
+    const LOST: &str;
+
+    /// Bind what's needed once the compositor has listed its globals. Returns `Ok(false)` if it's missing.
+    fn bind(&mut self, qhandle: &QueueHandle<Self>) -> anyhow::Result<bool>;
+
+    /// Checked after every dispatch, an error drops the connection.
+    fn check(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Listen with the state made by `new` until nobody is listening to `tx` anymore, or for good if the compositor
+/// lacks support. Reconnects with an exponential back off like [`WaylandBackend`](super::WaylandBackend), sending
+/// None in the mean time.
+pub(super) async fn listen<T, State: Listener>(
+    tx: watch::Sender<Option<T>>,
+    new: impl Fn(watch::Sender<Option<T>>) -> State,
+) {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    loop {
+        match listen_once(new(tx.clone()), &tx).await {
+            Ok(true) => return,
+            Ok(false) => {
+                error!("{}", State::UNSUPPORTED);
+                return
+            },
+            Err(e) => warn!(error=?e, retry_in=?backoff, "{}", State::LOST),
+        }
+        // Only notify if there was something to clear, a spurious change would read as e.g. resuming from idle.
+        tx.send_if_modified(|x| x.take().is_some());
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// Returns `Ok(false)` if unsupported and `Ok(true)` once nobody is listening anymore, see [`listen`].
+async fn listen_once<T, State: Listener>(mut state: State, tx: &watch::Sender<Option<T>>) -> anyhow::Result<bool> {
+    let conn = connect_to_env(Duration::ZERO).await?;
+    let mut event_queue = conn.new_event_queue();
+    let fd = AsyncFd::with_interest(
+        conn.backend().poll_fd().as_raw_fd(),
+        Interest::READABLE | Interest::WRITABLE,
+    )?;
+    let qh = event_queue.handle();
+    let _ = conn.display().get_registry(&qh, ());
+    // The compositor advertises every global before answering the sync, and the callback dies with the answer.
+    let sync = conn.display().sync(&qh, ());
+    time::timeout(ROUNDTRIP_TIMEOUT, async {
+        while sync.is_alive() {
+            dispatch(&conn, &mut event_queue, &fd, &mut state).await?;
+        }
+        anyhow::Ok(())
+    }).await.context("Timed out waiting for the Wayland compositor to list its globals")??;
+    if !state.bind(&qh)? {
+        return Ok(false)
+    }
+    flush(&conn)?;
+
+    loop {
+        tokio::select! {
+            res = dispatch(&conn, &mut event_queue, &fd, &mut state) => res?,
+            _ = tx.closed() => return Ok(true),
+        }
+        state.check()?;
+    }
+}
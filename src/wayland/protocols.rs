@@ -0,0 +1,46 @@
+// Bindings for the wlroots and KDE protocols not in wayland-protocols, generated from the XML in protocols/.
+#![allow(dead_code, non_camel_case_types, non_upper_case_globals, unused_imports, clippy::all)]
+
+pub(crate) mod foreign_toplevel {
+    use wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_client::backend as wayland_backend;
+        use wayland_client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocols/wlr-foreign-toplevel-management-unstable-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocols/wlr-foreign-toplevel-management-unstable-v1.xml");
+}
+
+pub(crate) mod kde_idle {
+    use wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_client::backend as wayland_backend;
+        use wayland_client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocols/kde-idle.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocols/kde-idle.xml");
+}
+
+pub(crate) mod layer_shell {
+    use wayland_client;
+    use wayland_client::protocol::*;
+    use wayland_protocols::xdg::shell::client::*;
+
+    pub mod __interfaces {
+        use wayland_client::backend as wayland_backend;
+        use wayland_client::protocol::__interfaces::*;
+        use wayland_protocols::xdg::shell::client::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocols/wlr-layer-shell-unstable-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocols/wlr-layer-shell-unstable-v1.xml");
+}
@@ -1,6 +1,17 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tracing::trace;
 use zbus_macros::proxy;
 use zbus::{fdo, zvariant};
 
+use crate::backend::{Backend, Health, Request};
+use crate::inhibitor::InhibitFlags;
+
+pub(crate) const NAME: &str = "logind";
+
 #[proxy(
     interface = "org.freedesktop.login1.Manager",
     default_path = "/org/freedesktop/login1",
@@ -8,23 +19,331 @@ use zbus::{fdo, zvariant};
 )]
 trait OrgFreedesktopLogin1 {
     fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> fdo::Result<zvariant::OwnedFd>;
+    #[zbus(name = "GetSessionByPID")]
+    fn get_session_by_pid(&self, pid: u32) -> fdo::Result<zvariant::OwnedObjectPath>;
+    fn get_user(&self, uid: u32) -> fdo::Result<zvariant::OwnedObjectPath>;
 }
 
-#[derive(Debug)]
+#[proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1",
+    async_name = "Login1User",
+)]
+trait OrgFreedesktopLogin1User {
+    /// The session the user's graphical session runs in.
+    #[zbus(property)]
+    fn display(&self) -> zbus::Result<(String, zvariant::OwnedObjectPath)>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1",
+    async_name = "Login1Session",
+)]
+trait OrgFreedesktopLogin1Session {
+    fn lock(&self) -> fdo::Result<()>;
+}
+
+/// What an org.freedesktop.login1 inhibitor lock applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum What {
+    Idle,
+    Sleep,
+    Shutdown,
+    HandlePowerKey,
+    HandleSuspendKey,
+    HandleHibernateKey,
+    HandleLidSwitch,
+    HandleRebootKey,
+}
+
+impl What {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            What::Idle => "idle",
+            What::Sleep => "sleep",
+            What::Shutdown => "shutdown",
+            What::HandlePowerKey => "handle-power-key",
+            What::HandleSuspendKey => "handle-suspend-key",
+            What::HandleHibernateKey => "handle-hibernate-key",
+            What::HandleLidSwitch => "handle-lid-switch",
+            What::HandleRebootKey => "handle-reboot-key",
+        }
+    }
+
+    /// Only sleep and shutdown can be delayed, everything else can only be blocked.
+    fn supports_delay(&self) -> bool {
+        matches!(self, What::Sleep | What::Shutdown)
+    }
+}
+
+impl FromStr for What {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "idle" => What::Idle,
+            "sleep" => What::Sleep,
+            "shutdown" => What::Shutdown,
+            "handle-power-key" => What::HandlePowerKey,
+            "handle-suspend-key" => What::HandleSuspendKey,
+            "handle-hibernate-key" => What::HandleHibernateKey,
+            "handle-lid-switch" => What::HandleLidSwitch,
+            "handle-reboot-key" => What::HandleRebootKey,
+            _ => return Err(format!("unknown inhibitor lock type {:?}", s)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Mode {
+    /// Prevent the operation for as long as the lock is held.
+    #[default]
+    Block,
+    /// Only delay the operation, for up to InhibitDelayMaxSec.
+    Delay,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Block => "block",
+            Mode::Delay => "delay",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Mode::Block),
+            "delay" => Ok(Mode::Delay),
+            _ => Err(format!("unknown inhibitor lock mode {:?}", s)),
+        }
+    }
+}
+
+/// Which locks to take for each of the inhibit flags.
+#[derive(Debug, Clone)]
+pub(crate) struct InhibitMapping {
+    pub suspend: Vec<What>,
+    pub logout: Vec<What>,
+    /// Mode of the sleep and shutdown locks, the rest are always blocking.
+    pub mode: Mode,
+}
+
+impl InhibitMapping {
+    /// The flags there are locks for, idle always is.
+    pub fn flags(&self) -> InhibitFlags {
+        let mut flags = InhibitFlags::IDLE;
+        flags.set(InhibitFlags::SUSPEND, !self.suspend.is_empty());
+        flags.set(InhibitFlags::LOGOUT, !self.logout.is_empty());
+        flags
+    }
+
+    /// The locks `flags` map to, each once, split into the blocking and the delaying ones.
+    fn locks(&self, flags: InhibitFlags) -> (Vec<What>, Vec<What>) {
+        let mut what = Vec::new();
+        let mut add = |xs: &[What]| for x in xs {
+            if !what.contains(x) {
+                what.push(*x);
+            }
+        };
+        if flags.contains(InhibitFlags::IDLE) {
+            add(&[What::Idle]);
+        }
+        if flags.contains(InhibitFlags::SUSPEND) {
+            add(&self.suspend);
+        }
+        if flags.contains(InhibitFlags::LOGOUT) {
+            add(&self.logout);
+        }
+
+        what.into_iter().partition(|x| !(self.mode == Mode::Delay && x.supports_delay()))
+    }
+}
+
+impl Default for InhibitMapping {
+    fn default() -> Self {
+        Self {
+            suspend: vec![What::Sleep],
+            logout: vec![What::Shutdown],
+            mode: Mode::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Login1Client {
     proxy: Login1<'static>,
+    mapping: InhibitMapping,
 }
 
 impl Login1Client {
-    pub async fn new() -> fdo::Result<Self> {
+    pub async fn new(mapping: InhibitMapping) -> fdo::Result<Self> {
         let connection = zbus::Connection::system().await?;
         let proxy = Login1::new(&connection, "org.freedesktop.login1").await?;
         Ok(Self {
             proxy,
+            mapping,
         })
     }
 
-    pub async fn inhibit_idle(&self, who: &str, why: &str) -> fdo::Result<zvariant::OwnedFd> {
-        self.proxy.inhibit("idle", who, why, "block").await
+    /// Take a single lock covering everything in `what`.
+    pub async fn inhibit(&self, what: &[What], who: &str, why: &str, mode: Mode) -> fdo::Result<zvariant::OwnedFd> {
+        let what = what.iter().map(What::as_str).collect::<Vec<_>>().join(":");
+        self.proxy.inhibit(&what, who, why, mode.as_str()).await
+    }
+
+    /// Take the locks `flags` map to, at most one for each mode.
+    pub async fn inhibit_flags(&self, flags: InhibitFlags, who: &str, why: &str) -> fdo::Result<Vec<zvariant::OwnedFd>> {
+        let (block, delay) = self.mapping.locks(flags);
+        let mut fds = Vec::new();
+        if !block.is_empty() {
+            fds.push(self.inhibit(&block, who, why, Mode::Block).await?);
+        }
+        if !delay.is_empty() {
+            fds.push(self.inhibit(&delay, who, why, Mode::Delay).await?);
+        }
+        Ok(fds)
+    }
+
+    /// Lock the session `pid` belongs to. Processes of systemd user services or D-Bus activated ones belong to
+    /// no session, for those lock the display session of `uid` instead.
+    pub async fn lock_session_of(&self, pid: u32, uid: u32) -> fdo::Result<()> {
+        let path = match self.proxy.get_session_by_pid(pid).await {
+            Ok(x) => x,
+            Err(e) => {
+                trace!(pid, error=?e, "No session for the process, using the user's display session");
+                let user = Login1User::builder(self.proxy.inner().connection())
+                    .path(self.proxy.get_user(uid).await?)?
+                    .build().await?;
+                let (id, path) = user.display().await?;
+                if id.is_empty() {
+                    return Err(fdo::Error::Failed(format!("User {} has no display session", uid)));
+                }
+                path
+            },
+        };
+        trace!(pid, %path, "Locking session");
+        Login1Session::builder(self.proxy.inner().connection())
+            .path(path)?
+            .build().await?
+            .lock().await
+    }
+}
+
+/// Holds org.freedesktop.login1 inhibitor locks for each cookie.
+#[derive(Debug)]
+pub(crate) struct Login1Backend {
+    client: Login1Client,
+    // NOTE: Must not be held across await points.
+    locks: Mutex<HashMap<u32, Vec<zvariant::OwnedFd>>>,
+}
+
+impl Login1Backend {
+    pub fn new(client: Login1Client) -> Self {
+        Self {
+            client,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for Login1Backend {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn flags(&self) -> InhibitFlags {
+        self.client.mapping.flags()
+    }
+
+    async fn acquire(&self, request: &Request<'_>) -> anyhow::Result<()> {
+        let fds = self.client.inhibit_flags(
+            request.flags,
+            env!("CARGO_PKG_NAME"),
+            &format!("{} {}", request.application_name, request.reason),
+        ).await?;
+        self.locks.lock()
+            .map_err(|e| anyhow::anyhow!("systemd-logind locks lock error: {:?}", e))?
+            .insert(request.cookie, fds);
+        Ok(())
+    }
+
+    /// org.freedesktop.login1 inhibitor locks get freed on drop, so dropping the fds is enough.
+    async fn release(&self, cookie: u32) -> anyhow::Result<()> {
+        self.locks.lock()
+            .map_err(|e| anyhow::anyhow!("systemd-logind locks lock error: {:?}", e))?
+            .remove(&cookie);
+        Ok(())
+    }
+
+    async fn health(&self) -> Health {
+        let peer = match fdo::PeerProxy::new(self.client.proxy.inner().connection(), "org.freedesktop.login1", "/org/freedesktop/login1").await {
+            Ok(x) => x,
+            Err(e) => return Health::Unhealthy(format!("{:?}", e)),
+        };
+        match peer.ping().await {
+            Ok(()) => Health::Healthy,
+            Err(e) => Health::Unhealthy(format!("{:?}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(suspend: &[What], logout: &[What], mode: Mode) -> InhibitMapping {
+        InhibitMapping {
+            suspend: suspend.to_vec(),
+            logout: logout.to_vec(),
+            mode,
+        }
+    }
+
+    #[test]
+    fn default_locks() {
+        let mapping = InhibitMapping::default();
+        assert_eq!(mapping.locks(InhibitFlags::IDLE), (vec![What::Idle], vec![]));
+        assert_eq!(mapping.locks(InhibitFlags::SUSPEND), (vec![What::Sleep], vec![]));
+        assert_eq!(
+            mapping.locks(InhibitFlags::IDLE | InhibitFlags::SUSPEND | InhibitFlags::LOGOUT),
+            (vec![What::Idle, What::Sleep, What::Shutdown], vec![]),
+        );
+        assert_eq!(mapping.locks(InhibitFlags::SWITCH_USER | InhibitFlags::AUTOMOUNT), (vec![], vec![]));
+    }
+
+    #[test]
+    fn deduplicated() {
+        let mapping = mapping(&[What::Sleep, What::HandleLidSwitch], &[What::Sleep, What::Shutdown], Mode::Block);
+        assert_eq!(
+            mapping.locks(InhibitFlags::SUSPEND | InhibitFlags::LOGOUT),
+            (vec![What::Sleep, What::HandleLidSwitch, What::Shutdown], vec![]),
+        );
+    }
+
+    #[test]
+    fn delay_only_sleep_and_shutdown() {
+        let mapping = mapping(&[What::Sleep, What::HandleLidSwitch], &[What::Shutdown], Mode::Delay);
+        assert_eq!(
+            mapping.locks(InhibitFlags::IDLE | InhibitFlags::SUSPEND | InhibitFlags::LOGOUT),
+            (vec![What::Idle, What::HandleLidSwitch], vec![What::Sleep, What::Shutdown]),
+        );
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(
+            InhibitMapping::default().flags(),
+            InhibitFlags::IDLE | InhibitFlags::SUSPEND | InhibitFlags::LOGOUT,
+        );
+        assert_eq!(mapping(&[], &[What::Shutdown], Mode::Block).flags(), InhibitFlags::IDLE | InhibitFlags::LOGOUT);
+        assert_eq!(mapping(&[], &[], Mode::Block).flags(), InhibitFlags::IDLE);
+        assert_eq!(mapping(&[], &[], Mode::Block).locks(InhibitFlags::SUSPEND | InhibitFlags::LOGOUT), (vec![], vec![]));
     }
 }